@@ -0,0 +1,92 @@
+//! Manifold api routes the backtest knows about but doesn't serve. These come
+//! from the endpoint list in README.md, so the list there is the one place to
+//! mark a route as done: every route in it that isn't marked `Y` gets the same
+//! "not supported in backtest" error, instead of warp's default rejection.
+//! These routes go after the real ones, so they only catch what those don't.
+
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::http::Method;
+use warp::Filter;
+
+use crate::server::ret_http_error;
+
+const README: &str = include_str!("../README.md");
+
+/// A route from the README, like `GET /v0/market/[marketId]/positions`
+struct KnownRoute {
+    method: Method,
+    segments: Vec<String>,
+}
+
+impl KnownRoute {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        *method == self.method
+            && segments.len() == self.segments.len()
+            && segments
+                .iter()
+                .zip(&self.segments)
+                .all(|(segment, pattern)| {
+                    // `[param]` matches any segment
+                    (pattern.starts_with('[') && !segment.is_empty()) || segment == pattern
+                })
+    }
+}
+
+/// The routes in the README's endpoint list that aren't marked `Y`
+fn unsupported_routes(readme: &str) -> Vec<KnownRoute> {
+    let Some((_, endpoint_list)) = readme.split_once("## endpoint list") else {
+        return Vec::new();
+    };
+
+    endpoint_list
+        .lines()
+        .skip_while(|line| !line.starts_with("-----"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("```"))
+        .filter_map(|line| {
+            let (status, route) = line.split_at(line.len().min(3));
+            if status.trim() == "Y" {
+                return None;
+            }
+
+            let mut parts = route.split_whitespace();
+            let method = parts.next()?.parse::<Method>().ok()?;
+            let path = parts.next()?;
+            Some(KnownRoute {
+                method,
+                segments: path
+                    .trim_matches('/')
+                    .split('/')
+                    .map(String::from)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+pub fn routes() -> BoxedFilter<(warp::reply::Response,)> {
+    let unsupported = Arc::new(unsupported_routes(README));
+
+    warp::method()
+        .and(warp::path::full())
+        .and_then(move |method: Method, path: warp::path::FullPath| {
+            let unsupported = unsupported.clone();
+            async move {
+                if unsupported
+                    .iter()
+                    .any(|route| route.matches(&method, path.as_str()))
+                {
+                    Ok(ret_http_error(
+                        501,
+                        format!("{method} {} is not supported in backtest", path.as_str()),
+                    ))
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .boxed()
+}
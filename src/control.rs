@@ -0,0 +1,399 @@
+//! Backtest-only control endpoints, under /backtest. These aren't part of the
+//! Manifold api; they're how a test harness drives the simulation.
+//!
+//! POST   /backtest/sessions                create a session, body `{"startTime": ms, "clockMode": mode}`
+//! GET    /backtest/sessions/[id]           get a session and its clock
+//! DELETE /backtest/sessions/[id]           delete a session and everything in it
+//! POST   /backtest/sessions/[id]/advance   move the clock, body `{"to": ms}` or `{"by": ms}`
+//! POST   /backtest/sessions/[id]/snapshot  save the session to a file in the snapshot directory, body `{"path": path}`
+//! POST   /backtest/sessions/[id]/fork      create a new session that copies this one
+//! POST   /backtest/sessions/restore        create a new session from a file in the snapshot directory, body `{"path": path}`
+//! POST   /backtest/sessions/[id]/clock     change how the clock moves, body is a ClockMode
+//!
+//! GET    /backtest/sessions/[id]/leaderboard  rank the historical users up to the clock, query
+//!                                          `by=profit|volume|calibration|brier&days=n&minBets=n&limit=n`
+//!
+//! Wakeups, stop points for the event-driven clock:
+//! POST   /backtest/sessions/[id]/wakeups                set one, body `{"at": ms}` or `{"marketId": id, "points": n}`
+//! GET    /backtest/sessions/[id]/wakeups                list them, including the ones that fired
+//! DELETE /backtest/sessions/[id]/wakeups/[wakeupId]     remove one
+//!
+//! Webhooks, see webhooks.rs:
+//! POST   /backtest/sessions/[id]/webhooks               add one, body `{"url": url, "events": [..], "marketIds": [..]}`
+//! GET    /backtest/sessions/[id]/webhooks               list them
+//! DELETE /backtest/sessions/[id]/webhooks/[webhookId]   remove one
+//!
+//! Lockstep mode:
+//! POST   /backtest/sessions/[id]/bots/[botId]          register a bot
+//! DELETE /backtest/sessions/[id]/bots/[botId]          unregister a bot
+//! POST   /backtest/sessions/[id]/bots/[botId]/ready    wait for the next event
+
+use serde::Deserialize;
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
+
+use crate::data_types::{ClockMode, LeaderboardMetric, WebhookEvent};
+use crate::engine::BacktestEngine;
+use crate::server::{ret_db_error, ret_http_error, strict_query};
+
+#[derive(Deserialize)]
+struct CreateSessionRequest {
+    #[serde(rename = "startTime")]
+    start_time: Option<u64>,
+    #[serde(rename = "clockMode", default)]
+    clock_mode: ClockMode,
+}
+
+#[derive(Deserialize)]
+struct AdvanceRequest {
+    to: Option<u64>,
+    by: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct WakeupRequest {
+    at: Option<u64>,
+    #[serde(rename = "marketId")]
+    market_id: Option<String>,
+    points: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct WebhookRequest {
+    url: String,
+    events: Vec<WebhookEvent>,
+    #[serde(rename = "marketIds", default)]
+    market_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LeaderboardQueryParams {
+    #[serde(default)]
+    by: LeaderboardMetric,
+    days: Option<u64>,
+    #[serde(rename = "minBets")]
+    min_bets: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct SnapshotFileRequest {
+    path: String,
+}
+
+pub fn routes(engine: BacktestEngine) -> BoxedFilter<(warp::reply::Response,)> {
+    let sessions = warp::path("backtest").and(warp::path("sessions"));
+
+    let engine_clone = engine.clone();
+    let create_session_endpoint = sessions
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::body::json::<CreateSessionRequest>())
+        .map(move |cr: CreateSessionRequest| {
+            match engine_clone.create_session(cr.start_time, cr.clock_mode) {
+                Ok(session) => warp::reply::json(&session).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let get_session_endpoint = sessions
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .map(
+            move |session_id: String| match engine_clone.get_session(Some(&session_id)) {
+                Ok(session) => warp::reply::json(&session).into_response(),
+                Err(e) => ret_db_error(e),
+            },
+        );
+
+    let engine_clone = engine.clone();
+    let delete_session_endpoint = sessions
+        .and(warp::delete())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .map(
+            move |session_id: String| match engine_clone.delete_session(&session_id) {
+                Ok(()) => {
+                    warp::reply::json(&serde_json::json!({ "id": session_id })).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            },
+        );
+
+    let engine_clone = engine.clone();
+    let advance_endpoint = sessions
+        .and(warp::post())
+        .and(warp::path::param())
+        .and(warp::path("advance"))
+        .and(warp::path::end())
+        .and(warp::body::json::<AdvanceRequest>())
+        .map(move |session_id: String, ar: AdvanceRequest| {
+            match engine_clone.advance_clock(&session_id, ar.to, ar.by) {
+                Ok(session) => warp::reply::json(&session).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let snapshot_endpoint = sessions
+        .and(warp::post())
+        .and(warp::path::param())
+        .and(warp::path("snapshot"))
+        .and(warp::path::end())
+        .and(warp::body::json::<SnapshotFileRequest>())
+        .map(move |session_id: String, sr: SnapshotFileRequest| {
+            match engine_clone.save_snapshot(&session_id, &sr.path) {
+                Ok(()) => {
+                    warp::reply::json(&serde_json::json!({ "id": session_id, "path": sr.path }))
+                        .into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let fork_endpoint = sessions
+        .and(warp::post())
+        .and(warp::path::param())
+        .and(warp::path("fork"))
+        .and(warp::path::end())
+        .map(
+            move |session_id: String| match engine_clone.fork_session(&session_id) {
+                Ok(session) => warp::reply::json(&session).into_response(),
+                Err(e) => ret_db_error(e),
+            },
+        );
+
+    let engine_clone = engine.clone();
+    let restore_endpoint = sessions
+        .and(warp::post())
+        .and(warp::path("restore"))
+        .and(warp::path::end())
+        .and(warp::body::json::<SnapshotFileRequest>())
+        .map(
+            move |sr: SnapshotFileRequest| match engine_clone.restore_snapshot(&sr.path) {
+                Ok(session) => warp::reply::json(&session).into_response(),
+                Err(e) => ret_db_error(e),
+            },
+        );
+
+    let engine_clone = engine.clone();
+    let clock_mode_endpoint = sessions
+        .and(warp::post())
+        .and(warp::path::param())
+        .and(warp::path("clock"))
+        .and(warp::path::end())
+        .and(warp::body::json::<ClockMode>())
+        .map(move |session_id: String, clock_mode: ClockMode| {
+            match engine_clone.set_clock_mode(&session_id, &clock_mode) {
+                Ok(session) => warp::reply::json(&session).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let leaderboard_endpoint = sessions
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path("leaderboard"))
+        .and(warp::path::end())
+        .and(strict_query::<LeaderboardQueryParams>())
+        .map(move |session_id: String, lq: LeaderboardQueryParams| {
+            match engine_clone.get_leaderboard(&session_id, lq.by, lq.days, lq.min_bets, lq.limit) {
+                Ok(leaderboard) => warp::reply::json(&leaderboard).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let wakeups = sessions
+        .and(warp::path::param::<String>())
+        .and(warp::path("wakeups"));
+
+    let engine_clone = engine.clone();
+    let add_wakeup_endpoint = wakeups
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::body::json::<WakeupRequest>())
+        .map(move |session_id: String, wr: WakeupRequest| {
+            match engine_clone.add_wakeup(&session_id, wr.at, wr.market_id.as_deref(), wr.points) {
+                Ok(wakeup) => warp::reply::json(&wakeup).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let get_wakeups_endpoint =
+        wakeups
+            .and(warp::get())
+            .and(warp::path::end())
+            .map(
+                move |session_id: String| match engine_clone.get_wakeups(&session_id) {
+                    Ok(wakeups) => warp::reply::json(&wakeups).into_response(),
+                    Err(e) => ret_db_error(e),
+                },
+            );
+
+    let engine_clone = engine.clone();
+    let delete_wakeup_endpoint = wakeups
+        .and(warp::delete())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .map(move |session_id: String, wakeup_id: String| {
+            match engine_clone.delete_wakeup(&session_id, &wakeup_id) {
+                Ok(()) => {
+                    warp::reply::json(&serde_json::json!({ "id": wakeup_id })).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let webhooks_path = sessions
+        .and(warp::path::param::<String>())
+        .and(warp::path("webhooks"));
+
+    let engine_clone = engine.clone();
+    let add_webhook_endpoint = webhooks_path
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::body::json::<WebhookRequest>())
+        .map(move |session_id: String, wr: WebhookRequest| {
+            match engine_clone.create_webhook(&session_id, &wr.url, wr.events, wr.market_ids) {
+                Ok(webhook) => warp::reply::json(&webhook).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let get_webhooks_endpoint =
+        webhooks_path
+            .and(warp::get())
+            .and(warp::path::end())
+            .map(
+                move |session_id: String| match engine_clone.list_webhooks(&session_id) {
+                    Ok(webhooks) => warp::reply::json(&webhooks).into_response(),
+                    Err(e) => ret_db_error(e),
+                },
+            );
+
+    let engine_clone = engine.clone();
+    let delete_webhook_endpoint = webhooks_path
+        .and(warp::delete())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .map(move |session_id: String, webhook_id: String| {
+            match engine_clone.delete_webhook(&session_id, &webhook_id) {
+                Ok(()) => {
+                    warp::reply::json(&serde_json::json!({ "id": webhook_id })).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let bots = sessions
+        .and(warp::path::param::<String>())
+        .and(warp::path("bots"))
+        .and(warp::path::param::<String>());
+
+    let engine_clone = engine.clone();
+    let register_bot_endpoint = bots.and(warp::post()).and(warp::path::end()).map(
+        move |session_id: String, bot_id: String| match engine_clone
+            .register_bot(&session_id, &bot_id)
+        {
+            Ok(()) => warp::reply::json(&serde_json::json!({ "id": session_id, "botId": bot_id }))
+                .into_response(),
+            Err(e) => ret_db_error(e),
+        },
+    );
+
+    // leaving can move the clock, so this runs on the blocking pool
+    let engine_clone = engine.clone();
+    let unregister_bot_endpoint = bots.and(warp::delete()).and(warp::path::end()).and_then(
+        move |session_id: String, bot_id: String| {
+            let engine = engine_clone.clone();
+            async move {
+                let unregistered = tokio::task::spawn_blocking({
+                    let (session_id, bot_id) = (session_id.clone(), bot_id.clone());
+                    move || engine.unregister_bot(&session_id, &bot_id)
+                })
+                .await;
+
+                Ok::<_, warp::Rejection>(match unregistered {
+                    Ok(Ok(())) => {
+                        warp::reply::json(&serde_json::json!({ "id": session_id, "botId": bot_id }))
+                            .into_response()
+                    }
+                    Ok(Err(e)) => ret_db_error(e),
+                    Err(e) => ret_http_error(500, e.to_string()),
+                })
+            }
+        },
+    );
+
+    let engine_clone = engine.clone();
+    let ready_endpoint = bots
+        .and(warp::post())
+        .and(warp::path("ready"))
+        .and(warp::path::end())
+        .and_then(move |session_id: String, bot_id: String| {
+            let engine = engine_clone.clone();
+            async move {
+                Ok::<_, warp::Rejection>(match engine.bot_ready(&session_id, &bot_id).await {
+                    Ok(session) => warp::reply::json(&session).into_response(),
+                    Err(e) => ret_db_error(e),
+                })
+            }
+        });
+
+    // boxed in groups, since one long chain of filters takes minutes to compile
+    let session_endpoints = create_session_endpoint
+        .or(restore_endpoint)
+        .unify()
+        .or(get_session_endpoint)
+        .unify()
+        .or(delete_session_endpoint)
+        .unify()
+        .or(advance_endpoint)
+        .unify()
+        .or(snapshot_endpoint)
+        .unify()
+        .or(fork_endpoint)
+        .unify()
+        .or(clock_mode_endpoint)
+        .unify()
+        .or(leaderboard_endpoint)
+        .unify()
+        .boxed();
+
+    let wakeup_endpoints = add_wakeup_endpoint
+        .or(get_wakeups_endpoint)
+        .unify()
+        .or(delete_wakeup_endpoint)
+        .unify()
+        .boxed();
+
+    let webhook_endpoints = add_webhook_endpoint
+        .or(get_webhooks_endpoint)
+        .unify()
+        .or(delete_webhook_endpoint)
+        .unify()
+        .boxed();
+
+    let bot_endpoints = register_bot_endpoint
+        .or(unregister_bot_endpoint)
+        .unify()
+        .or(ready_endpoint)
+        .unify()
+        .boxed();
+
+    session_endpoints
+        .or(wakeup_endpoints)
+        .unify()
+        .or(webhook_endpoints)
+        .unify()
+        .or(bot_endpoints)
+        .unify()
+        .boxed()
+}
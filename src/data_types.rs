@@ -137,6 +137,205 @@ pub struct User {
     pub profit_cached: HashMap<TimePeriod, f64>,
 }
 
+/// A user reconstructed from the historical bets and markets. The backtest data
+/// only has their names and avatars, so whatever can't be worked out from the
+/// bets (as of the session clock) is zeroed or null, and named in
+/// `backtestUnknownFields`, which isn't part of the Manifold api.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoricalUser {
+    #[serde(flatten)]
+    pub user: User,
+
+    #[serde(rename = "backtestUnknownFields")]
+    pub unknown_fields: Vec<String>,
+}
+
+/// A user from GET /v0/user, who is either one of the session's simulated users
+/// or a historical one
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum AnyUser {
+    Simulated(User),
+    Historical(HistoricalUser),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Group {
+    /// from <https://docs.manifold.markets/api#get-v0groups>
+    pub id: String,
+    pub slug: String,
+    pub name: String,
+
+    /// Rich text content. See https://tiptap.dev/guide/output#option-1-json
+    pub about: Option<JSONContent>,
+
+    #[serde(rename = "creatorId")]
+    pub creator_id: String,
+
+    #[serde(rename = "createdTime")]
+    pub created_time: u64,
+
+    #[serde(rename = "mostRecentActivityTime")]
+    pub most_recent_activity_time: Option<u64>,
+
+    #[serde(rename = "mostRecentContractAddedTime")]
+    pub most_recent_contract_added_time: Option<u64>,
+
+    #[serde(rename = "anyoneCanJoin")]
+    pub anyone_can_join: bool,
+
+    #[serde(rename = "totalContracts")]
+    pub total_contracts: u64,
+
+    #[serde(rename = "totalMembers")]
+    pub total_members: u64,
+
+    #[serde(rename = "postIds")]
+    pub post_ids: Vec<String>,
+}
+
+/// A group reconstructed from the markets' group slugs. The backtest data has
+/// nothing else about groups, so a group's id and name are its slug, it was
+/// created with its first market, and the fields that can't be worked out are
+/// zeroed or null, and named in `backtestUnknownFields`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoricalGroup {
+    #[serde(flatten)]
+    pub group: Group,
+
+    #[serde(rename = "backtestUnknownFields")]
+    pub unknown_fields: Vec<String>,
+}
+
+/// What the backtest leaderboard ranks historical users by
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum LeaderboardMetric {
+    /// Profit made over the window, highest first
+    #[default]
+    #[serde(rename = "profit")]
+    Profit,
+    /// Mana bet over the window, highest first
+    #[serde(rename = "volume")]
+    Volume,
+    /// Expected calibration error of the probabilities bet to, lowest first
+    #[serde(rename = "calibration")]
+    Calibration,
+    /// Brier score of the probabilities bet to, lowest first
+    #[serde(rename = "brier")]
+    Brier,
+}
+
+/// A row of the backtest leaderboard, which isn't part of the Manifold api
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaderboardEntry {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub username: String,
+    pub name: String,
+    pub score: f64,
+
+    /// The bets the score is from
+    #[serde(rename = "betCount")]
+    pub bet_count: u64,
+}
+
+/// The parameters of GET /v0/markets. The ones after `group_id` are
+/// backtest-only, and are as of the session clock, so `is_resolved` means
+/// resolved by then and `min_volume` counts the bets placed by then.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MarketQuery {
+    pub limit: Option<i64>,
+    pub sort: Option<String>,
+    pub order: Option<String>,
+    /// The id of the market to start after
+    pub before: Option<String>,
+    #[serde(rename = "userId")]
+    pub user_id: Option<String>,
+    /// A group slug, see Group
+    #[serde(rename = "groupId")]
+    pub group_id: Option<String>,
+    #[serde(rename = "outcomeType")]
+    pub outcome_type: Option<MarketOutcomeType>,
+    pub mechanism: Option<MarketMechanism>,
+    #[serde(rename = "isResolved")]
+    pub is_resolved: Option<bool>,
+    #[serde(rename = "createdAfter")]
+    pub created_after: Option<u64>,
+    #[serde(rename = "createdBefore")]
+    pub created_before: Option<u64>,
+    #[serde(rename = "closeTimeAfter")]
+    pub close_time_after: Option<u64>,
+    #[serde(rename = "closeTimeBefore")]
+    pub close_time_before: Option<u64>,
+    #[serde(rename = "minVolume")]
+    pub min_volume: Option<f64>,
+}
+
+/// The parameters of GET /v0/search-markets
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SearchMarketsQuery {
+    pub term: Option<String>,
+    pub sort: Option<String>,
+    pub filter: Option<String>,
+    #[serde(rename = "contractType")]
+    pub contract_type: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// The parameters of GET /v0/bets. The ones after `answer_id` are
+/// backtest-only.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BetQuery {
+    #[serde(rename = "userId")]
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    #[serde(rename = "contractId")]
+    pub contract_id: Option<String>,
+    #[serde(rename = "contractSlug")]
+    pub contract_slug: Option<String>,
+    pub limit: Option<i64>,
+    /// The id of the bet the page follows in the `order`
+    pub before: Option<String>,
+    /// The id of the bet the page comes just ahead of in the `order`
+    pub after: Option<String>,
+    pub order: Option<String>,
+    /// Only `open-limit`, for limit orders that haven't filled, expired or been
+    /// cancelled by the session clock
+    pub kinds: Option<String>,
+    #[serde(rename = "filterRedemptions")]
+    pub filter_redemptions: Option<bool>,
+    #[serde(rename = "answerId")]
+    pub answer_id: Option<String>,
+    /// Bet size, so sales count too
+    #[serde(rename = "minAmount")]
+    pub min_amount: Option<f64>,
+    #[serde(rename = "excludeAntes")]
+    pub exclude_antes: Option<bool>,
+    /// Bets placed after this, in ms
+    #[serde(rename = "afterTime")]
+    pub after_time: Option<u64>,
+    /// Bets placed before this, in ms
+    #[serde(rename = "beforeTime")]
+    pub before_time: Option<u64>,
+}
+
+/// The body of POST /v0/bet
+#[derive(Deserialize, Debug, Clone)]
+pub struct BetRequest {
+    #[serde(rename = "contractId")]
+    pub contract_id: String,
+    pub amount: f64,
+    pub outcome: String,
+    #[serde(rename = "limitProb")]
+    pub limit_prob: Option<f64>,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LiteMarket {
     /// from <https://docs.manifold.markets/api#get-v0markets>
@@ -249,22 +448,22 @@ pub struct Answer {
     pub id: String,
 
     #[serde(rename = "createdTime")]
-    created_time: u64,
+    pub created_time: u64,
 
     #[serde(rename = "avatarURL")]
-    avatar_url: Option<String>,
+    pub avatar_url: Option<String>,
 
-    username: Option<String>,
-    number: Option<u32>,
-    name: Option<String>,
+    pub username: Option<String>,
+    pub number: Option<u32>,
+    pub name: Option<String>,
 
     #[serde(rename = "contractId")]
-    contract_id: String,
+    pub contract_id: String,
 
     pub text: String,
 
     #[serde(rename = "userId")]
-    user_id: String,
+    pub user_id: String,
     pub probability: f64,
 }
 
@@ -284,15 +483,15 @@ pub struct FullMarket {
 
     /// Rich text content. See https://tiptap.dev/guide/output#option-1-json
     #[serde(skip_deserializing)]
-    description: Option<JSONContent>,
+    pub description: Option<JSONContent>,
 
     /// string description without formatting, images, or embeds
     #[serde(rename = "textDescription")]
-    text_description: Option<String>,
+    pub text_description: Option<String>,
 
     /// groups which the market is a part of
     #[serde(rename = "groupSlugs")]
-    group_slugs: Option<Vec<String>>,
+    pub group_slugs: Option<Vec<String>>,
 }
 
 /// A single position in a market
@@ -302,79 +501,79 @@ pub struct ContractMetric {
 
     /// The contract ID
     #[serde(rename = "contractId")]
-    contract_id: String,
+    pub contract_id: String,
 
     /// Includes day, week, month. Can be undefined.
-    from: Option<HashMap<String, PeriodMetric>>,
+    pub from: Option<HashMap<String, PeriodMetric>>,
 
     /// Indicates if there are no shares
     #[serde(rename = "hasNoShares")]
-    has_no_shares: bool,
+    pub has_no_shares: bool,
 
     /// Indicates if there are shares
     #[serde(rename = "hasShares")]
-    has_shares: bool,
+    pub has_shares: bool,
 
     /// Indicates if there are yes shares
     #[serde(rename = "hasYesShares")]
-    has_yes_shares: bool,
+    pub has_yes_shares: bool,
 
     /// Invested amount
-    invested: f64,
+    pub invested: f64,
 
     /// Loan amount
-    loan: f64,
+    pub loan: f64,
 
     /// Maximum shares outcome, can be null
     #[serde(rename = "maxSharesOutcome")]
-    max_shares_outcome: Option<String>,
+    pub max_shares_outcome: Option<String>,
 
     /// Payout amount
-    payout: f64,
+    pub payout: f64,
 
     /// Profit amount
-    profit: f64,
+    pub profit: f64,
 
     /// Profit percentage
     #[serde(rename = "profitPercent")]
-    profit_percent: f64,
+    pub profit_percent: f64,
 
     /// Total shares
     #[serde(rename = "totalShares")]
-    total_shares: HashMap<MarketOutcome, f64>,
+    pub total_shares: HashMap<MarketOutcome, f64>,
 
     /// User ID
     #[serde(rename = "userId")]
-    user_id: String,
+    pub user_id: String,
 
     /// User name
     #[serde(rename = "userName")]
-    user_name: String,
+    pub user_name: String,
 
     /// User avatar URL
     #[serde(rename = "userAvatarUrl")]
-    user_avatar_url: String,
+    pub user_avatar_url: String,
 
     /// Last bet time
     #[serde(rename = "lastBetTime")]
-    last_bet_time: u64,
+    pub last_bet_time: u64,
 }
 
 /// Metrics for a specific period
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PeriodMetric {
     /// Profit amount
-    profit: f64,
+    pub profit: f64,
     /// Profit percentage
     #[serde(rename = "profitPercent")]
-    profit_percent: f64,
+    pub profit_percent: f64,
     /// Invested amount
-    invested: f64,
+    pub invested: f64,
     /// Previous value
     #[serde(rename = "prevValue")]
-    prev_value: f64,
+    pub prev_value: f64,
     /// Current value
-    value: f64,
+    pub value: f64,
 }
 
 /// Represents a bet
@@ -473,76 +672,55 @@ impl Display for Bet {
     }
 }
 
-/// NumericBet extends Bet with additional fields
-#[derive(Serialize, Deserialize, Debug)]
-pub struct NumericBet {
-    #[serde(flatten)]
-    bet: Bet,
-    value: f64,
-    #[serde(rename = "allOutcomeShares")]
-    all_outcome_shares: HashMap<String, f64>,
-    #[serde(rename = "allBetAmounts")]
-    all_bet_amounts: HashMap<String, f64>,
-}
-
-/// LimitBet is a Bet with LimitProps flattened into it
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LimitBet {
-    #[serde(flatten)]
-    bet: Bet,
-    #[serde(flatten)]
-    limit_props: LimitProps,
-}
-
 /// Properties specific to a limit bet
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LimitProps {
     /// Amount of mana in the order
     #[serde(rename = "orderAmount")]
-    order_amount: f64,
+    pub order_amount: f64,
     /// [0, 1]. Bet to this probability.
     #[serde(rename = "limitProb")]
-    limit_prob: f64,
+    pub limit_prob: f64,
     /// Whether all of the bet amount has been filled.
     #[serde(rename = "isFilled")]
-    is_filled: bool,
+    pub is_filled: bool,
     /// Whether to prevent any further fills.
     #[serde(rename = "isCancelled")]
-    is_cancelled: bool,
+    pub is_cancelled: bool,
     /// A record of each transaction that partially (or fully) fills the order amount.
-    fills: Vec<Fill>,
+    pub fills: Vec<Fill>,
     /// ms since epoch. Optional.
     #[serde(rename = "expiresAt", skip_serializing_if = "Option::is_none")]
-    expires_at: Option<u64>,
+    pub expires_at: Option<u64>,
 }
 
 /// Represents a fill in a bet
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Fill {
     /// Timestamp of the fill
-    timestamp: u64,
+    pub timestamp: u64,
     /// The id the bet matched against, or null if the bet was matched by the pool.
     #[serde(rename = "matchedBetId")]
-    matched_bet_id: Option<String>,
+    pub matched_bet_id: Option<String>,
     /// Amount involved in the fill
-    amount: f64,
+    pub amount: f64,
     /// Shares involved in the fill
-    shares: f64,
+    pub shares: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Fees {
     /// Fee for the creator
     #[serde(rename = "creatorFee")]
-    creator_fee: f64,
+    pub creator_fee: f64,
 
     /// Fee for the platform
     #[serde(rename = "platformFee")]
-    platform_fee: f64,
+    pub platform_fee: f64,
 
     /// Fee for liquidity
     #[serde(rename = "liquidityFee")]
-    liquidity_fee: f64,
+    pub liquidity_fee: f64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -552,3 +730,158 @@ pub enum Visibility {
     Unlisted,
     Private,
 }
+
+/// A backtest session: a simulated clock, plus the simulated users and bets
+/// that are layered over the (read-only) historical data.
+/// Not part of the Manifold api.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub id: String,
+
+    #[serde(rename = "createdTime")]
+    pub created_time: u64,
+
+    /// The simulated time, in milliseconds since epoch. Historical data from after
+    /// this time is hidden. None means there is no clock, and all of the backtest
+    /// data is visible (this is how the default session works).
+    #[serde(rename = "clockTime")]
+    pub clock_time: Option<u64>,
+
+    #[serde(rename = "clockMode", default)]
+    pub clock_mode: ClockMode,
+}
+
+/// How a session's clock moves forward. The advance endpoint works in every mode.
+/// Not part of the Manifold api.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "mode")]
+pub enum ClockMode {
+    /// Only moves through the advance endpoint
+    #[default]
+    #[serde(rename = "manual")]
+    Manual,
+
+    /// Moves to the next historical event once every registered bot has said it's
+    /// ready, or once `timeout_ms` (wall clock) has passed since the last move
+    #[serde(rename = "lockstep")]
+    Lockstep {
+        #[serde(rename = "timeoutMs")]
+        timeout_ms: u64,
+    },
+
+    /// Moves continuously, `rate` times faster than the wall clock
+    #[serde(rename = "realtime")]
+    Realtime {
+        rate: f64,
+
+        /// Wall clock time when the clock was last set, filled in by the server
+        #[serde(rename = "anchorWallTime", default)]
+        anchor_wall_time: u64,
+
+        /// Session clock when the clock was last set, filled in by the server
+        #[serde(rename = "anchorClockTime", default)]
+        anchor_clock_time: u64,
+    },
+
+    /// Jumps to the next event relevant to the session's bots, once they've
+    /// been idle for `idleMs`
+    #[serde(rename = "event-driven")]
+    EventDriven {
+        #[serde(rename = "idleMs")]
+        idle_ms: u64,
+    },
+}
+
+/// A stop point for the event-driven clock: either a simulated time, or a
+/// binary market moving more than `points` percentage points away from its
+/// probability when the wakeup was set. Wakeups fire once.
+/// Not part of the Manifold api.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Wakeup {
+    pub id: String,
+
+    /// The session clock when the wakeup was set
+    #[serde(rename = "createdTime")]
+    pub created_time: u64,
+
+    pub at: Option<u64>,
+
+    #[serde(rename = "marketId")]
+    pub market_id: Option<String>,
+
+    pub points: Option<f64>,
+
+    /// The market's probability when the wakeup was set
+    #[serde(rename = "referenceProbability")]
+    pub reference_probability: Option<f64>,
+
+    /// The session time the wakeup fired at, if it has
+    #[serde(rename = "firedTime")]
+    pub fired_time: Option<u64>,
+}
+
+/// The kinds of session events a webhook can be sent
+/// Not part of the Manifold api.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WebhookEvent {
+    #[serde(rename = "market-created")]
+    MarketCreated,
+
+    /// A bet, historical or simulated, on one of the webhook's markets
+    #[serde(rename = "bet")]
+    Bet,
+
+    /// One of the session's limit orders filling
+    #[serde(rename = "limit-fill")]
+    LimitFill,
+
+    #[serde(rename = "resolution")]
+    Resolution,
+}
+
+/// A local url that a session's events are POSTed to as the clock passes them.
+/// Not part of the Manifold api.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub id: String,
+
+    pub url: String,
+
+    pub events: Vec<WebhookEvent>,
+
+    /// The markets whose bets are sent, for `bet` events
+    #[serde(rename = "marketIds", default)]
+    pub market_ids: Vec<String>,
+
+    #[serde(rename = "createdTime")]
+    pub created_time: u64,
+}
+
+/// A simulated user in a session snapshot, along with the api key it belongs to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotUser {
+    #[serde(rename = "apiKey")]
+    pub api_key: String,
+
+    pub user: User,
+}
+
+/// Everything needed to recreate a session: its clock, its users (and their
+/// balances), its simulated bets, including open limit orders, and its
+/// wakeups. Positions are worked out from the bets.
+/// Not part of the Manifold api.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionSnapshot {
+    #[serde(rename = "clockTime")]
+    pub clock_time: Option<u64>,
+
+    #[serde(rename = "clockMode", default)]
+    pub clock_mode: ClockMode,
+
+    pub users: Vec<SnapshotUser>,
+
+    pub bets: Vec<Bet>,
+
+    #[serde(default)]
+    pub wakeups: Vec<Wakeup>,
+}
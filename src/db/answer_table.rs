@@ -0,0 +1,141 @@
+use log::debug;
+use rusqlite::{named_params, params, Connection, Result, Row};
+
+use crate::data_types::{Answer, FullMarket};
+use crate::db::db_common;
+use crate::db::errors::RowParsingError;
+use crate::db::market_table::{iter_over_markets, MARKETS_JSON};
+
+pub fn create_answer_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE answers (
+            id TEXT NOT NULL,
+            contract_id TEXT NOT NULL,
+            created_time BIGINT NOT NULL,
+            avatar_url TEXT,
+            username TEXT,
+            number INTEGER,
+            name TEXT,
+            text TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            probability FLOAT NOT NULL,
+            PRIMARY KEY (contract_id, id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn bulk_insert_answers(conn: &mut Connection, markets: &[FullMarket]) -> Result<usize> {
+    let stmt_str = "INSERT OR IGNORE INTO answers (
+        id, contract_id, created_time, avatar_url, username, number, name, text,
+        user_id, probability
+    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10);";
+
+    let mut count = 0;
+    let tx = conn.transaction()?;
+
+    {
+        let mut stmt = tx.prepare(stmt_str)?;
+        for answer in markets.iter().flat_map(|m| m.answers.iter().flatten()) {
+            count += stmt.execute(params![
+                answer.id,
+                answer.contract_id,
+                answer.created_time,
+                answer.avatar_url,
+                answer.username,
+                answer.number,
+                answer.name,
+                answer.text,
+                answer.user_id,
+                answer.probability,
+            ])?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(count)
+}
+
+/// The answers of market `:contract_id` that had been added by `:as_of`, with
+/// each one's probability rolled back to its last bet. Bets on the other
+/// answers move it too, so this is only an estimate. With a NULL `:as_of`,
+/// this is just the answers table.
+const ANSWERS_AS_OF: &str = "
+    SELECT
+      a.id, a.created_time, a.avatar_url, a.username, a.number, a.name, a.contract_id,
+      a.text, a.user_id,
+      CASE
+        WHEN :as_of IS NULL THEN a.probability
+        ELSE COALESCE(
+          (SELECT b.prob_after FROM bets b
+            WHERE b.contract_id = a.contract_id AND b.answer_id = a.id
+              AND b.created_time <= :as_of
+            ORDER BY b.created_time DESC LIMIT 1),
+          (SELECT b.prob_before FROM bets b
+            WHERE b.contract_id = a.contract_id AND b.answer_id = a.id
+            ORDER BY b.created_time ASC LIMIT 1),
+          a.probability)
+      END AS probability
+    FROM answers a
+    WHERE a.contract_id = :contract_id AND (:as_of IS NULL OR a.created_time <= :as_of)
+    ORDER BY a.number ASC, a.created_time ASC";
+
+pub fn rusqlite_row_to_answer(row: &Row) -> Result<Answer, RowParsingError> {
+    Ok(Answer {
+        id: row.get(0)?,
+        created_time: row.get(1)?,
+        avatar_url: row.get(2)?,
+        username: row.get(3)?,
+        number: row.get(4)?,
+        name: row.get(5)?,
+        contract_id: row.get(6)?,
+        text: row.get(7)?,
+        user_id: row.get(8)?,
+        probability: row.get(9)?,
+    })
+}
+
+/// The market's answers as of `as_of`, or None if it's not a market with
+/// answers
+pub fn get_answers_as_of(
+    conn: &Connection,
+    contract_id: &str,
+    as_of: Option<u64>,
+) -> Result<Option<Vec<Answer>>, RowParsingError> {
+    let has_answers = conn
+        .prepare("SELECT 1 FROM answers WHERE contract_id = ?1")?
+        .exists(params![contract_id])?;
+    if !has_answers {
+        return Ok(None);
+    }
+
+    let mut stmt = conn.prepare(ANSWERS_AS_OF)?;
+    let answer_iter = stmt.query_map(
+        named_params! { ":contract_id": contract_id, ":as_of": as_of },
+        |row| Ok(rusqlite_row_to_answer(row)),
+    )?;
+
+    let mut answers = Vec::new();
+    for maybe_answer in answer_iter {
+        answers.push(maybe_answer??);
+    }
+    Ok(Some(answers))
+}
+
+pub fn init_answer_table(conn: &mut Connection) -> Result<usize, RowParsingError> {
+    let mut count = 0;
+
+    if !db_common::table_exists(conn, "answers")? {
+        debug!("creating 'answers' table");
+        create_answer_table(conn)?;
+
+        let markets = iter_over_markets(MARKETS_JSON)?;
+        count = bulk_insert_answers(conn, &markets)?;
+        debug!("{count} answers inserted");
+    } else {
+        debug!("found 'answers' table");
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,94 @@
+use log::debug;
+use rusqlite::{named_params, params, Connection, OptionalExtension, Result};
+
+use crate::db::db_common;
+use crate::db::session_table::DEFAULT_SESSION_ID;
+
+pub fn create_api_key_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE api_keys (
+            session_id TEXT NOT NULL,
+            key TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY (session_id, key)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn insert_api_key(
+    conn: &Connection,
+    session_id: &str,
+    key: &str,
+    user_id: &str,
+) -> Result<usize> {
+    conn.execute(
+        "INSERT INTO api_keys (session_id, key, user_id) VALUES (?1, ?2, ?3)",
+        params![session_id, key, user_id],
+    )
+}
+
+/// Returns the id of the simulated user that owns `key` in the session, if there is one
+pub fn get_user_id_for_api_key(
+    conn: &Connection,
+    session_id: &str,
+    key: &str,
+) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT user_id FROM api_keys WHERE session_id = :session_id AND key = :key",
+        named_params! { ":session_id": session_id, ":key": key },
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// All of the (api key, user id) pairs in a session
+pub fn get_api_keys_for_session(
+    conn: &Connection,
+    session_id: &str,
+) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT key, user_id FROM api_keys WHERE session_id = ?1")?;
+    let rows = stmt.query_map(params![session_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// api keys used to be global, before there were sessions. Those keys all
+/// belong to the default session now.
+fn migrate_sessionless_api_keys(conn: &mut Connection) -> Result<()> {
+    let has_session_id = conn
+        .prepare("SELECT 1 FROM pragma_table_info('api_keys') WHERE name = 'session_id'")?
+        .exists([])?;
+
+    if has_session_id {
+        return Ok(());
+    }
+
+    debug!("moving sessionless api keys to the default session");
+
+    let tx = conn.transaction()?;
+    tx.execute("ALTER TABLE api_keys RENAME TO api_keys_old", [])?;
+    create_api_key_table(&tx)?;
+    tx.execute(
+        "INSERT INTO api_keys (session_id, key, user_id) SELECT ?1, key, user_id FROM api_keys_old",
+        params![DEFAULT_SESSION_ID],
+    )?;
+    tx.execute("DROP TABLE api_keys_old", [])?;
+    tx.commit()
+}
+
+pub fn init_api_key_table(conn: &mut Connection) -> Result<usize> {
+    if !db_common::table_exists(conn, "api_keys")? {
+        debug!("creating 'api_keys' table");
+        create_api_key_table(conn)?;
+    } else {
+        debug!("found 'api_keys' table");
+        migrate_sessionless_api_keys(conn)?;
+    }
+
+    let num_rows =
+        db_common::count_rows(conn, "api_keys").expect("failed to count rows in api_keys table");
+    debug!("{num_rows} api keys registered");
+
+    Ok(num_rows)
+}
@@ -7,55 +7,41 @@ use crate::data_types::{Bet, Fees, LimitProps, Visibility};
 use crate::db::db_common;
 use crate::db::errors::RowParsingError;
 
-fn iter_over_bets(bets_dir: &str) -> impl Iterator<Item = Vec<Bet>> {
-    let paths = fs::read_dir(bets_dir).unwrap_or_else(|err| {
-        panic!("Error reading directory: {}", err);
-    });
-
-    // Count the number of files that will be processed
-    let total_files = paths
-        .filter(|entry| {
-            if let Ok(e) = entry {
-                let path = e.path();
-                path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("json")
-            } else {
-                false
-            }
-        })
-        .count();
+fn iter_over_bets(
+    bets_dir: &str,
+) -> Result<impl Iterator<Item = Result<Vec<Bet>, RowParsingError>>, RowParsingError> {
+    let read_error =
+        |e: std::io::Error| RowParsingError::Generic(format!("couldn't read {bets_dir}: {e}"));
+
+    // Find the files that will be processed
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(bets_dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("json") {
+            paths.push(path);
+        }
+    }
 
     // Create a new progress bar with the total count
-    let progress_bar = ProgressBar::new(total_files as u64);
+    let progress_bar = ProgressBar::new(paths.len() as u64);
     progress_bar.set_style(
         ProgressStyle::default_bar()
             .template(
                 "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
             )
-            .unwrap()
+            .map_err(|e| RowParsingError::Generic(e.to_string()))?
             .progress_chars("#>-"),
     );
 
-    // Reset the iterator to read the directory again
-    let paths = fs::read_dir(bets_dir).unwrap();
+    Ok(paths.into_iter().map(move |path| {
+        let file_as_string = fs::read_to_string(&path).map_err(|e| {
+            RowParsingError::Generic(format!("couldn't read {}: {e}", path.display()))
+        })?;
 
-    paths.filter_map(move |entry| {
-        if let Ok(e) = entry {
-            let path = e.path();
+        progress_bar.inc(1); // Increment the progress bar
 
-            if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("json") {
-                let file_as_string =
-                    fs::read_to_string(&path).expect("couldn't read file to string");
-
-                progress_bar.inc(1); // Increment the progress bar
-
-                serde_json::from_str(&file_as_string).expect("failed to parse json into Bet struct")
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    })
+        Ok(serde_json::from_str(&file_as_string)?)
+    }))
 }
 
 pub fn create_bet_table(conn: &Connection) -> Result<()> {
@@ -90,7 +76,7 @@ pub fn create_bet_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
-pub fn bulk_insert_bets(conn: &mut Connection, bets: &Vec<Bet>) -> Result<usize> {
+pub fn bulk_insert_bets(conn: &mut Connection, bets: &[Bet]) -> Result<usize> {
     let stmt_str = "INSERT INTO bets (
             id, user_id, user_avatar_url, user_name, user_username, contract_id, answer_id,
             created_time, amount, loan_amount, outcome, shares,
@@ -193,7 +179,23 @@ pub fn rusqlite_row_to_bet(row: &Row) -> Result<Bet, RowParsingError> {
     })
 }
 
-pub fn init_bet_table(conn: &mut Connection) -> Result<usize> {
+/// The bet as it was at `as_of`. A limit order's fills from after then haven't
+/// happened yet, so they're dropped, and its amount, shares and isFilled are
+/// worked out from the fills before.
+pub fn bet_as_of(mut bet: Bet, as_of: Option<u64>) -> Bet {
+    if let (Some(as_of), Some(limit_props)) = (as_of, bet.limit_props.as_mut()) {
+        if limit_props.fills.iter().any(|fill| fill.timestamp > as_of) {
+            limit_props.fills.retain(|fill| fill.timestamp <= as_of);
+            // an order only fills with its last fill
+            limit_props.is_filled = false;
+            bet.amount = limit_props.fills.iter().map(|fill| fill.amount).sum();
+            bet.shares = limit_props.fills.iter().map(|fill| fill.shares).sum();
+        }
+    }
+    bet
+}
+
+pub fn init_bet_table(conn: &mut Connection) -> Result<usize, RowParsingError> {
     if !db_common::table_exists(conn, "bets")? {
         debug!("creating 'bets' table");
         create_bet_table(conn)?;
@@ -204,12 +206,12 @@ pub fn init_bet_table(conn: &mut Connection) -> Result<usize> {
     let mut count = 0;
 
     // see TODO comment in init_market_table
-    let num_rows = db_common::count_rows(conn, "bets").expect("failed to count rows in bets table");
+    let num_rows = db_common::count_rows(conn, "bets")?;
     if num_rows == 0 {
         debug!("inserting bets");
 
-        for bets in iter_over_bets("backtest-data/bets") {
-            count += bulk_insert_bets(conn, &bets)?;
+        for bets in iter_over_bets("backtest-data/bets")? {
+            count += bulk_insert_bets(conn, &bets?)?;
         }
 
         debug!("{count} bets inserted...");
@@ -225,5 +227,27 @@ pub fn init_bet_table(conn: &mut Connection) -> Result<usize> {
     )?;
     debug!("'bets' index created (or found) in {:?}", start.elapsed());
 
+    // for rolling markets back to the session clock, see MARKETS_AS_OF
+    let start = std::time::Instant::now();
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS bets_contract_index ON bets (contract_id, created_time);",
+        [],
+    )?;
+    debug!(
+        "'bets' contract index created (or found) in {:?}",
+        start.elapsed()
+    );
+
+    // for users' bets as of the session clock
+    let start = std::time::Instant::now();
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS bets_user_index ON bets (user_id, created_time);",
+        [],
+    )?;
+    debug!(
+        "'bets' user index created (or found) in {:?}",
+        start.elapsed()
+    );
+
     Ok(count)
 }
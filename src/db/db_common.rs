@@ -1,34 +1,64 @@
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
+use rand::distributions::{Alphanumeric, DistString};
 use rusqlite::{params, Connection};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::db::answer_table::init_answer_table;
+use crate::db::api_key_table::init_api_key_table;
 use crate::db::bet_table::init_bet_table;
+use crate::db::errors::RowParsingError;
+use crate::db::group_table::init_group_table;
+use crate::db::historical_user_table::init_historical_user_table;
 use crate::db::market_table::init_market_table;
+use crate::db::session_table::init_session_table;
+use crate::db::sim_bet_table::init_sim_bet_table;
 use crate::db::user_table::init_user_table;
+use crate::db::wakeup_table::init_wakeup_table;
+use crate::db::webhook_table::init_webhook_table;
 
 pub fn get_db_connection_pool() -> Result<Arc<Pool<SqliteConnectionManager>>, r2d2::Error> {
     let manager = SqliteConnectionManager::file("mmmbacktest.db");
     Ok(Arc::new(r2d2::Pool::new(manager)?))
 }
 
-pub fn setup_db() -> Arc<Pool<SqliteConnectionManager>> {
-    let connection_pool = get_db_connection_pool().expect("failed to get db connection pool");
+pub fn setup_db() -> Result<Arc<Pool<SqliteConnectionManager>>, RowParsingError> {
+    let connection_pool = get_db_connection_pool()
+        .map_err(|e| RowParsingError::Generic(format!("failed to get db connection pool: {e}")))?;
 
-    let mut conn = get_db_connection(connection_pool.clone());
-    init_market_table(&mut conn).expect("failed to init market table");
-    init_bet_table(&mut conn).expect("failed to init bet table");
-    init_user_table(&mut conn).expect("failed to init user table");
+    let mut conn = try_get_db_connection(connection_pool.clone())?;
+    init_market_table(&mut conn)?;
+    init_answer_table(&mut conn)?;
+    init_group_table(&mut conn)?;
+    init_bet_table(&mut conn)?;
+    init_user_table(&mut conn)?;
+    init_historical_user_table(&mut conn)?;
+    init_session_table(&mut conn)?;
+    init_api_key_table(&mut conn)?;
+    init_sim_bet_table(&mut conn)?;
+    init_wakeup_table(&mut conn)?;
+    init_webhook_table(&mut conn)?;
 
-    connection_pool
+    Ok(connection_pool)
 }
 
+/// For the background tasks, which have nowhere to send the error. See
+/// try_get_db_connection.
 pub fn get_db_connection(
     connection_pool: Arc<Pool<SqliteConnectionManager>>,
 ) -> PooledConnection<SqliteConnectionManager> {
-    let conn = connection_pool
-        .get()
-        .expect("failed to get db connection from the pool");
+    try_get_db_connection(connection_pool).expect("failed to get db connection from the pool")
+}
+
+/// A connection from the pool, or an error if none came free before the pool's
+/// timeout
+pub fn try_get_db_connection(
+    connection_pool: Arc<Pool<SqliteConnectionManager>>,
+) -> Result<PooledConnection<SqliteConnectionManager>, RowParsingError> {
+    let conn = connection_pool.get().map_err(|e| {
+        RowParsingError::Generic(format!("failed to get db connection from the pool: {e}"))
+    })?;
 
     // https://phiresky.github.io/blog/2020/sqlite-performance-tuning/
     let query = "
@@ -38,7 +68,7 @@ pub fn get_db_connection(
         pragma mmap_size = 30000000000;";
 
     {
-        let mut stmt = conn.prepare(query).expect("failed to prep stmt hm what");
+        let mut stmt = conn.prepare(query)?;
         match stmt.query([]) {
             Ok(_) => {}
             Err(e) => {
@@ -47,7 +77,7 @@ pub fn get_db_connection(
         };
     }
 
-    conn
+    Ok(conn)
 }
 
 pub fn table_exists(conn: &Connection, table_name: &str) -> rusqlite::Result<bool> {
@@ -56,8 +86,45 @@ pub fn table_exists(conn: &Connection, table_name: &str) -> rusqlite::Result<boo
     Ok(exists)
 }
 
+/// For tables that have grown a column since they were first created.
+/// Returns whether the column was added.
+pub fn add_column_if_missing(
+    conn: &Connection,
+    table_name: &str,
+    column_name: &str,
+    column_definition: &str,
+) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT 1 FROM pragma_table_info('{table_name}') WHERE name = ?1"
+    ))?;
+
+    if !stmt.exists(params![column_name])? {
+        log::debug!("adding column '{column_name}' to '{table_name}'");
+        conn.execute(
+            &format!("ALTER TABLE {table_name} ADD COLUMN {column_name} {column_definition}"),
+            [],
+        )?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
 pub fn count_rows(conn: &Connection, table_name: &str) -> rusqlite::Result<usize> {
     let mut stmt = conn.prepare(&format!("SELECT COUNT(*) FROM {}", table_name))?;
     let count: usize = stmt.query_row([], |row| row.get(0))?;
     Ok(count)
 }
+
+/// A random id that looks like the ones Manifold uses
+pub fn new_id() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 20)
+}
+
+/// Wall clock time in milliseconds since epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the epoch")
+        .as_millis() as u64
+}
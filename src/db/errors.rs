@@ -7,6 +7,10 @@ pub enum RowParsingError {
     RusqliteError(rusqlite::Error),
     SerdeError(serde_json::Error),
     MarketNotFound(String),
+    UserNotFound(String),
+    GroupNotFound(String),
+    SessionNotFound(String),
+    InvalidRequest(String),
 }
 
 impl fmt::Display for RowParsingError {
@@ -16,6 +20,10 @@ impl fmt::Display for RowParsingError {
             RowParsingError::RusqliteError(e) => write!(f, "Rusqlite error: {}", e),
             RowParsingError::SerdeError(e) => write!(f, "Serde JSON error: {}", e),
             RowParsingError::MarketNotFound(e) => write!(f, "Market Not Found error: {}", e),
+            RowParsingError::UserNotFound(e) => write!(f, "User Not Found error: {}", e),
+            RowParsingError::GroupNotFound(e) => write!(f, "Group Not Found error: {}", e),
+            RowParsingError::SessionNotFound(e) => write!(f, "Session Not Found error: {}", e),
+            RowParsingError::InvalidRequest(e) => write!(f, "Invalid Request error: {}", e),
         }
     }
 }
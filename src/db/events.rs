@@ -0,0 +1,276 @@
+//! What happened in a session between two looks at it, so it can be pushed
+//! to bots instead of them having to poll for it.
+
+use rusqlite::{named_params, params, Connection};
+use std::collections::BTreeSet;
+
+use crate::data_types::{Bet, LiteMarket, Session};
+use crate::db::bet_table::{bet_as_of, rusqlite_row_to_bet};
+use crate::db::errors::RowParsingError;
+use crate::db::market_table::{rusqlite_row_to_litemarket, MARKETS_AS_OF};
+
+pub enum SessionEvent {
+    /// A market was created
+    NewContract(LiteMarket),
+
+    /// A bet was placed, either historically or by one of the session's users
+    NewBet(Bet),
+
+    /// One of the session's limit orders was filled
+    OrderFilled(Bet),
+
+    /// A market resolved
+    Resolved(LiteMarket),
+}
+
+/// The markets that one kind of event is wanted for
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Markets {
+    #[default]
+    None,
+    All,
+    Only(BTreeSet<String>),
+}
+
+impl Markets {
+    /// Adds `other`'s markets to these
+    pub fn extend(&mut self, other: &Markets) {
+        match (&mut *self, other) {
+            (_, Markets::None) | (Markets::All, _) => {}
+            (Markets::Only(ids), Markets::Only(other_ids)) => ids.extend(other_ids.iter().cloned()),
+            (this, other) => *this = other.clone(),
+        }
+    }
+
+    pub fn insert(&mut self, market_id: &str) {
+        self.extend(&Markets::Only(BTreeSet::from([market_id.to_string()])));
+    }
+
+    /// The `:market_ids` parameter for `market_condition`: NULL for every
+    /// market, or a JSON array of market ids
+    fn param(&self) -> Option<String> {
+        match self {
+            Markets::Only(ids) => Some(serde_json::to_string(ids).unwrap()),
+            _ => None,
+        }
+    }
+}
+
+/// Restricts `column` to the markets in the `:market_ids` parameter
+fn market_condition(column: &str) -> String {
+    format!("(:market_ids IS NULL OR {column} IN (SELECT value FROM json_each(:market_ids)))")
+}
+
+/// Which events to read. Only what's wanted is queried, since most listeners
+/// only care about a few markets.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventFilter {
+    pub new_contracts: bool,
+    pub bets: Markets,
+    pub order_fills: Markets,
+    pub resolutions: Markets,
+}
+
+impl EventFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == EventFilter::default()
+    }
+
+    /// Adds the events `other` wants to these
+    pub fn extend(&mut self, other: &EventFilter) {
+        self.new_contracts |= other.new_contracts;
+        self.bets.extend(&other.bets);
+        self.order_fills.extend(&other.order_fills);
+        self.resolutions.extend(&other.resolutions);
+    }
+}
+
+/// Where a session was the last time its events were read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EventCursor {
+    clock_time: Option<u64>,
+    sim_bet_rowid: i64,
+}
+
+fn max_sim_bet_rowid(conn: &Connection, session_id: &str) -> Result<i64, RowParsingError> {
+    let rowid = conn.query_row(
+        "SELECT COALESCE(MAX(rowid), 0) FROM sim_bets WHERE session_id = ?1",
+        params![session_id],
+        |row| row.get(0),
+    )?;
+    Ok(rowid)
+}
+
+/// A cursor at the session as it is now, so that only later events are read
+pub fn event_cursor(conn: &Connection, session: &Session) -> Result<EventCursor, RowParsingError> {
+    Ok(EventCursor {
+        clock_time: session.clock_time,
+        sim_bet_rowid: max_sim_bet_rowid(conn, &session.id)?,
+    })
+}
+
+fn query_markets(
+    conn: &Connection,
+    condition: &str,
+    from: u64,
+    to: u64,
+    markets: &Markets,
+) -> Result<Vec<LiteMarket>, RowParsingError> {
+    let market_condition = market_condition("id");
+    let query = format!("SELECT * FROM ({MARKETS_AS_OF}) WHERE {condition} AND {market_condition}");
+    let mut stmt = conn.prepare(&query)?;
+    let market_iter = stmt.query_map(
+        named_params! { ":as_of": to, ":from": from, ":market_ids": markets.param() },
+        |row| Ok(rusqlite_row_to_litemarket(row)),
+    )?;
+
+    let mut markets = Vec::new();
+    for maybe_market in market_iter {
+        markets.push(maybe_market??);
+    }
+    Ok(markets)
+}
+
+fn query_bets(
+    conn: &Connection,
+    query: &str,
+    params: &[(&str, &dyn rusqlite::ToSql)],
+) -> Result<Vec<Bet>, RowParsingError> {
+    let mut stmt = conn.prepare(query)?;
+    let bet_iter = stmt.query_map(params, |row| Ok(rusqlite_row_to_bet(row)))?;
+
+    let mut bets = Vec::new();
+    for maybe_bet in bet_iter {
+        bets.push(maybe_bet??);
+    }
+    Ok(bets)
+}
+
+/// The events that `filter` wants from the session since `cursor`, in the order
+/// they happened, along with a cursor to read on from next time
+pub fn get_events_since(
+    conn: &Connection,
+    session: &Session,
+    cursor: EventCursor,
+    filter: &EventFilter,
+) -> Result<(Vec<SessionEvent>, EventCursor), RowParsingError> {
+    let to = event_cursor(conn, session)?;
+    let events = get_events_between(conn, session, cursor, to, filter)?;
+    Ok((events, to))
+}
+
+/// The events that `filter` wants from the session after `from`, up to and
+/// including `to`, in the order they happened. Nothing is queried if `filter`
+/// doesn't want anything.
+pub fn get_events_between(
+    conn: &Connection,
+    session: &Session,
+    from: EventCursor,
+    to: EventCursor,
+    filter: &EventFilter,
+) -> Result<Vec<SessionEvent>, RowParsingError> {
+    if filter.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // (time, event) pairs, sorted by time at the end
+    let mut events: Vec<(u64, SessionEvent)> = Vec::new();
+
+    if let (Some(from), Some(to)) = (from.clock_time, to.clock_time) {
+        if to > from {
+            if filter.new_contracts {
+                let condition = "created_time > :from";
+                for market in query_markets(conn, condition, from, to, &Markets::All)? {
+                    events.push((market.created_time, SessionEvent::NewContract(market)));
+                }
+            }
+
+            if filter.bets != Markets::None {
+                let historical_bets = query_bets(
+                    conn,
+                    &format!(
+                        "SELECT * FROM bets
+                        WHERE created_time > :from AND created_time <= :to AND {}
+                        ORDER BY created_time ASC",
+                        market_condition("contract_id")
+                    ),
+                    named_params! {
+                        ":from": from,
+                        ":to": to,
+                        ":market_ids": filter.bets.param(),
+                    },
+                )?;
+                for bet in historical_bets {
+                    let bet = bet_as_of(bet, Some(to));
+                    events.push((bet.created_time, SessionEvent::NewBet(bet)));
+                }
+            }
+
+            if filter.order_fills != Markets::None {
+                let filled_orders = query_bets(
+                    conn,
+                    &format!(
+                        "SELECT * FROM sim_bets
+                        WHERE session_id = :session_id AND {} AND EXISTS (
+                          SELECT 1 FROM json_each(limit_props, '$.fills')
+                          WHERE json_extract(value, '$.timestamp') > :from
+                            AND json_extract(value, '$.timestamp') <= :to
+                            AND json_extract(value, '$.timestamp') > sim_bets.created_time
+                        )",
+                        market_condition("contract_id")
+                    ),
+                    named_params! {
+                        ":session_id": session.id,
+                        ":from": from,
+                        ":to": to,
+                        ":market_ids": filter.order_fills.param(),
+                    },
+                )?;
+                for bet in filled_orders {
+                    let filled_time = bet
+                        .limit_props
+                        .as_ref()
+                        .and_then(|lp| lp.fills.iter().map(|fill| fill.timestamp).max())
+                        .unwrap_or(to);
+                    events.push((filled_time, SessionEvent::OrderFilled(bet)));
+                }
+            }
+
+            if filter.resolutions != Markets::None {
+                let condition = "resolution_time > :from AND resolution_time <= :as_of";
+                for market in query_markets(conn, condition, from, to, &filter.resolutions)? {
+                    let resolution_time = market.resolution_time.unwrap_or(to);
+                    events.push((resolution_time, SessionEvent::Resolved(market)));
+                }
+            }
+        }
+    }
+
+    // bets placed since the last look. Orders that filled as they were placed
+    // only show up here, not as fills.
+    if filter.bets != Markets::None {
+        let new_sim_bets = query_bets(
+            conn,
+            &format!(
+                "SELECT * FROM sim_bets
+                WHERE session_id = :session_id AND rowid > :from_rowid AND rowid <= :to_rowid
+                  AND {}
+                ORDER BY rowid ASC",
+                market_condition("contract_id")
+            ),
+            named_params! {
+                ":session_id": session.id,
+                ":from_rowid": from.sim_bet_rowid,
+                ":to_rowid": to.sim_bet_rowid,
+                ":market_ids": filter.bets.param(),
+            },
+        )?;
+        for bet in new_sim_bets {
+            events.push((bet.created_time, SessionEvent::NewBet(bet)));
+        }
+    }
+
+    events.sort_by_key(|(time, _)| *time);
+
+    Ok(events.into_iter().map(|(_, event)| event).collect())
+}
@@ -0,0 +1,129 @@
+use log::debug;
+use rusqlite::{Connection, Result, Row};
+
+use crate::data_types::{Group, HistoricalGroup};
+use crate::db::db_common;
+use crate::db::errors::RowParsingError;
+
+/// The Group fields that can't be worked out from the backtest data
+pub const UNKNOWN_GROUP_FIELDS: [&str; 6] = [
+    "name",
+    "about",
+    "creatorId",
+    "anyoneCanJoin",
+    "totalMembers",
+    "postIds",
+];
+
+pub fn create_group_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE groups (
+            id TEXT PRIMARY KEY,
+            slug TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE group_markets (
+            group_id TEXT NOT NULL,
+            market_id TEXT NOT NULL,
+            PRIMARY KEY (group_id, market_id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Fills the groups tables from the markets' group slugs
+pub fn derive_groups(conn: &Connection) -> Result<usize> {
+    conn.execute(
+        "INSERT OR IGNORE INTO group_markets (group_id, market_id)
+        SELECT j.value, m.id
+        FROM markets m, json_each(m.group_slugs) j
+        WHERE json_type(m.group_slugs) = 'array'",
+        [],
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO groups (id, slug, name)
+        SELECT DISTINCT group_id, group_id, group_id FROM group_markets",
+        [],
+    )
+}
+
+/// Groups that had a market by `:as_of`, with their market counts and activity
+/// as of then. A NULL `:as_of` means all of them. Only the group with id `:id`
+/// or slug `:slug` is worked out if either is given, so looking up one group
+/// doesn't aggregate all of them.
+pub const GROUPS_AS_OF: &str = "
+    SELECT
+      g.id, g.slug, g.name,
+      MIN(m.created_time) AS created_time,
+      -- the outer MAX is over the group's markets, the inner one is per market
+      MAX(MAX(
+        m.created_time,
+        COALESCE((SELECT MAX(b.created_time) FROM bets b
+          WHERE b.contract_id = m.id AND (:as_of IS NULL OR b.created_time <= :as_of)), 0)
+      )) AS most_recent_activity_time,
+      MAX(m.created_time) AS most_recent_contract_added_time,
+      COUNT(*) AS total_contracts
+    FROM groups g
+    JOIN group_markets gm ON gm.group_id = g.id
+    JOIN markets m ON m.id = gm.market_id
+    WHERE (:as_of IS NULL OR m.created_time <= :as_of)
+      AND (:id IS NULL OR g.id = :id)
+      AND (:slug IS NULL OR g.slug = :slug)
+    GROUP BY g.id";
+
+pub fn rusqlite_row_to_group(row: &Row) -> Result<HistoricalGroup, RowParsingError> {
+    let group = Group {
+        id: row.get(0)?,
+        slug: row.get(1)?,
+        name: row.get(2)?,
+        about: None,
+        creator_id: String::new(),
+        created_time: row.get(3)?,
+        most_recent_activity_time: row.get(4)?,
+        most_recent_contract_added_time: row.get(5)?,
+        anyone_can_join: true,
+        total_contracts: row.get(6)?,
+        total_members: 0,
+        post_ids: Vec::new(),
+    };
+
+    Ok(HistoricalGroup {
+        group,
+        unknown_fields: UNKNOWN_GROUP_FIELDS
+            .iter()
+            .map(|field| field.to_string())
+            .collect(),
+    })
+}
+
+pub fn init_group_table(conn: &mut Connection) -> Result<usize> {
+    if !db_common::table_exists(conn, "groups")? {
+        debug!("creating 'groups' tables");
+        create_group_tables(conn)?;
+    } else {
+        debug!("found 'groups' table");
+    }
+
+    // see TODO comment in init_market_table
+    let mut num_rows =
+        db_common::count_rows(conn, "groups").expect("failed to count rows in groups table");
+    if num_rows == 0 {
+        debug!("deriving groups from market group slugs");
+        num_rows = derive_groups(conn)?;
+        debug!("{num_rows} groups inserted...");
+    } else {
+        // TODO
+        debug!("there are {num_rows} (instead of 0) rows, so not inserting anything");
+    }
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS group_markets_market_index ON group_markets (market_id);",
+        [],
+    )?;
+
+    Ok(num_rows)
+}
@@ -0,0 +1,147 @@
+use log::debug;
+use rusqlite::{Connection, Result, Row};
+use std::collections::HashMap;
+
+use crate::data_types::{HistoricalUser, TimePeriod, User};
+use crate::db::db_common;
+use crate::db::errors::RowParsingError;
+
+/// The User fields that can't be worked out from the backtest data
+pub const UNKNOWN_USER_FIELDS: [&str; 13] = [
+    "balance",
+    "totalDeposits",
+    "bio",
+    "bannerUrl",
+    "website",
+    "twitterHandle",
+    "discordHandle",
+    "isBot",
+    "isAdmin",
+    "isTrustworthy",
+    "isBannedFromPosting",
+    "userDeleted",
+    "currentBettingStreak",
+];
+
+pub fn create_historical_user_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE historical_users (
+            id TEXT PRIMARY KEY,
+            created_time BIGINT NOT NULL,
+            name TEXT NOT NULL,
+            username TEXT NOT NULL,
+            avatar_url TEXT
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Fills the historical users table from everyone who bet or created a market.
+/// A user's created time is their first appearance, and their names and avatar
+/// are from their latest one.
+pub fn derive_historical_users(conn: &Connection) -> Result<usize> {
+    conn.execute(
+        "WITH appearances AS (
+            SELECT user_id AS id, user_name AS name, user_username AS username,
+              user_avatar_url AS avatar_url, created_time
+            FROM bets
+            UNION ALL
+            SELECT creator_id, creator_name, creator_username, creator_avatar_url, created_time
+            FROM markets
+        ),
+        latest AS (
+            -- sqlite takes the bare columns from the row with the max
+            SELECT id, name, username, avatar_url, MAX(created_time)
+            FROM appearances
+            WHERE username IS NOT NULL
+            GROUP BY id
+        ),
+        first AS (
+            SELECT id, MIN(created_time) AS created_time FROM appearances GROUP BY id
+        )
+        INSERT INTO historical_users (id, created_time, name, username, avatar_url)
+        SELECT first.id, first.created_time, COALESCE(latest.name, latest.username, first.id),
+          COALESCE(latest.username, first.id), latest.avatar_url
+        FROM first LEFT JOIN latest ON latest.id = first.id",
+        [],
+    )
+}
+
+/// Historical users who had shown up by `:as_of`, with their last bet time as
+/// of then. A NULL `:as_of` means all of them.
+pub const HISTORICAL_USERS_AS_OF: &str = "
+    SELECT
+      h.id, h.created_time, h.name, h.username, h.avatar_url,
+      (SELECT MAX(b.created_time) FROM bets b
+        WHERE b.user_id = h.id AND (:as_of IS NULL OR b.created_time <= :as_of))
+        AS last_bet_time
+    FROM historical_users h
+    WHERE :as_of IS NULL OR h.created_time <= :as_of";
+
+/// Converts a row of HISTORICAL_USERS_AS_OF into a User, with the fields that
+/// aren't in the backtest data zeroed, and listed as unknown. profit_cached is
+/// left empty, to be filled in by the caller.
+pub fn rusqlite_row_to_historical_user(row: &Row) -> Result<HistoricalUser, RowParsingError> {
+    let username: String = row.get(3)?;
+
+    let user = User {
+        id: row.get(0)?,
+        created_time: row.get(1)?,
+        name: row.get(2)?,
+        url: Some(format!("https://manifold.markets/{username}")),
+        username,
+        avatar_url: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+        bio: None,
+        banner_url: None,
+        website: None,
+        twitter_handle: None,
+        discord_handle: None,
+        is_bot: None,
+        is_admin: None,
+        is_trustworthy: None,
+        is_banned_from_posting: None,
+        user_deleted: None,
+        balance: 0.0,
+        total_deposits: 0.0,
+        last_bet_time: row.get(5)?,
+        current_betting_streak: None,
+        profit_cached: HashMap::<TimePeriod, f64>::new(),
+    };
+
+    Ok(HistoricalUser {
+        user,
+        unknown_fields: UNKNOWN_USER_FIELDS
+            .iter()
+            .map(|field| field.to_string())
+            .collect(),
+    })
+}
+
+pub fn init_historical_user_table(conn: &mut Connection) -> Result<usize> {
+    if !db_common::table_exists(conn, "historical_users")? {
+        debug!("creating 'historical_users' table");
+        create_historical_user_table(conn)?;
+    } else {
+        debug!("found 'historical_users' table");
+    }
+
+    // see TODO comment in init_market_table
+    let mut num_rows = db_common::count_rows(conn, "historical_users")
+        .expect("failed to count rows in historical_users table");
+    if num_rows == 0 {
+        debug!("deriving historical users from bets and markets");
+        num_rows = derive_historical_users(conn)?;
+        debug!("{num_rows} historical users inserted...");
+    } else {
+        // TODO
+        debug!("there are {num_rows} (instead of 0) rows, so not inserting anything");
+    }
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS historical_users_username_index ON historical_users (username);",
+        [],
+    )?;
+
+    Ok(num_rows)
+}
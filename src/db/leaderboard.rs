@@ -0,0 +1,182 @@
+//! Leaderboard of the historical users over a window ending at the session
+//! clock, so a strategy can pick out who was good without seeing past it.
+//! Forecasts are scored by the probability each bet moved its market to,
+//! against how the market resolved; only BINARY markets that resolved YES or
+//! NO by the end of the window count.
+
+use rusqlite::{named_params, Connection};
+use std::collections::HashMap;
+
+use crate::data_types::{LeaderboardEntry, LeaderboardMetric};
+use crate::db::check_limit;
+use crate::db::errors::RowParsingError;
+use crate::db::historical_user_table::HISTORICAL_USERS_AS_OF;
+use crate::db::profit::{get_profits_as_of, period_end, DAY_MS};
+
+/// The bets placed in (:start, :end], leaving out antes and redemptions,
+/// which aren't anyone's trading decision
+const WINDOW_BETS: &str = "
+    SELECT * FROM bets
+    WHERE NOT is_ante AND NOT is_redemption
+      AND (:start IS NULL OR created_time > :start)
+      AND (:end IS NULL OR created_time <= :end)";
+
+/// (bet count, volume) per user over the window
+fn get_volumes(
+    conn: &Connection,
+    start: Option<u64>,
+    end: Option<u64>,
+) -> Result<HashMap<String, (u64, f64)>, RowParsingError> {
+    let query =
+        format!("SELECT user_id, COUNT(*), SUM(ABS(amount)) FROM ({WINDOW_BETS}) GROUP BY user_id");
+    let mut stmt = conn.prepare(&query)?;
+    let rows = stmt.query_map(named_params! { ":start": start, ":end": end }, |row| {
+        Ok((row.get::<_, String>(0)?, (row.get(1)?, row.get(2)?)))
+    })?;
+
+    let mut volumes = HashMap::new();
+    for row in rows {
+        let (user_id, volume) = row?;
+        volumes.insert(user_id, volume);
+    }
+    Ok(volumes)
+}
+
+/// (bet count, score) per user over the window, for the forecast metrics
+fn get_forecast_scores(
+    conn: &Connection,
+    metric: LeaderboardMetric,
+    start: Option<u64>,
+    end: Option<u64>,
+) -> Result<HashMap<String, (u64, f64)>, RowParsingError> {
+    let forecasts = format!(
+        "SELECT b.user_id, b.prob_after AS prob,
+          CASE WHEN m.resolution = 'YES' THEN 1.0 ELSE 0.0 END AS outcome
+        FROM ({WINDOW_BETS}) b JOIN markets m ON m.id = b.contract_id
+        WHERE b.answer_id IS NULL
+          AND m.outcome_type = '\"BINARY\"'
+          AND m.is_resolved AND m.resolution IN ('YES', 'NO')
+          AND (:end IS NULL OR m.resolution_time <= :end)"
+    );
+
+    let query = match metric {
+        LeaderboardMetric::Brier => format!(
+            "SELECT user_id, COUNT(*), AVG((prob - outcome) * (prob - outcome))
+            FROM ({forecasts})
+            GROUP BY user_id"
+        ),
+        // expected calibration error: how far off each tenth of the
+        // probability range was, weighted by how many bets fell in it
+        _ => format!(
+            "WITH buckets AS (
+                SELECT user_id, COUNT(*) AS n, AVG(prob) AS mean_prob, AVG(outcome) AS mean_outcome
+                FROM ({forecasts})
+                GROUP BY user_id, MIN(CAST(prob * 10 AS INTEGER), 9)
+            )
+            SELECT user_id, SUM(n), SUM(n * ABS(mean_prob - mean_outcome)) / SUM(n)
+            FROM buckets
+            GROUP BY user_id"
+        ),
+    };
+
+    let mut stmt = conn.prepare(&query)?;
+    let rows = stmt.query_map(named_params! { ":start": start, ":end": end }, |row| {
+        Ok((row.get::<_, String>(0)?, (row.get(1)?, row.get(2)?)))
+    })?;
+
+    let mut scores = HashMap::new();
+    for row in rows {
+        let (user_id, score) = row?;
+        scores.insert(user_id, score);
+    }
+    Ok(scores)
+}
+
+/// Historical users who had shown up by `as_of`, ranked by `metric` over the
+/// `days` before it, or over everything before it without `days`. Users with
+/// fewer than `min_bets` bets counted towards their score are left out.
+pub fn get_leaderboard(
+    conn: &Connection,
+    as_of: Option<u64>,
+    metric: LeaderboardMetric,
+    days: Option<u64>,
+    min_bets: Option<u64>,
+    limit: Option<i64>,
+) -> Result<Vec<LeaderboardEntry>, RowParsingError> {
+    let limit = match limit {
+        None => 100,
+        limit => check_limit(limit)?,
+    };
+    let start = match days {
+        Some(days) => Some(period_end(conn, as_of)?.saturating_sub(days * DAY_MS)),
+        None => None,
+    };
+
+    let scores = match metric {
+        LeaderboardMetric::Profit => {
+            // positions held since before the window still count towards its
+            // profit, but only the bets in the window count towards minBets
+            let counts = get_volumes(conn, start, as_of)?;
+            let mut profits = get_profits_as_of(conn, None, None, as_of)?;
+            if let Some(start) = start {
+                for (user_id, profit_before) in get_profits_as_of(conn, None, None, Some(start))? {
+                    *profits.entry(user_id).or_default() -= profit_before;
+                }
+            }
+
+            profits
+                .into_iter()
+                .map(|(user_id, profit)| {
+                    let count = counts.get(&user_id).map_or(0, |(count, _)| *count);
+                    (user_id, (count, profit))
+                })
+                .collect()
+        }
+        LeaderboardMetric::Volume => get_volumes(conn, start, as_of)?,
+        LeaderboardMetric::Calibration | LeaderboardMetric::Brier => {
+            get_forecast_scores(conn, metric, start, as_of)?
+        }
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, username, name FROM ({HISTORICAL_USERS_AS_OF})"
+    ))?;
+    let user_iter = stmt.query_map(named_params! { ":as_of": as_of }, |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+
+    let mut entries = Vec::new();
+    for maybe_user in user_iter {
+        let (user_id, username, name) = maybe_user?;
+        let Some(&(bet_count, score)) = scores.get(&user_id) else {
+            continue;
+        };
+        if bet_count < min_bets.unwrap_or(1) {
+            continue;
+        }
+
+        entries.push(LeaderboardEntry {
+            user_id,
+            username,
+            name,
+            score,
+            bet_count,
+        });
+    }
+
+    match metric {
+        LeaderboardMetric::Profit | LeaderboardMetric::Volume => {
+            entries.sort_by(|a, b| b.score.total_cmp(&a.score))
+        }
+        LeaderboardMetric::Calibration | LeaderboardMetric::Brier => {
+            entries.sort_by(|a, b| a.score.total_cmp(&b.score))
+        }
+    }
+    entries.truncate(limit as usize);
+
+    Ok(entries)
+}
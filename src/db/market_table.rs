@@ -6,10 +6,13 @@ use crate::data_types::{FullMarket, LiteMarket, MarketMechanism, MarketOutcomeTy
 use crate::db::db_common;
 use crate::db::errors::RowParsingError;
 
-fn iter_over_markets(market_json: &String) -> Vec<FullMarket> {
-    let file_as_string = fs::read_to_string(market_json).unwrap();
-    let markets: Vec<FullMarket> = serde_json::from_str(&file_as_string).unwrap();
-    markets
+pub const MARKETS_JSON: &str = "backtest-data/manifold-dump-markets-04082023.json";
+
+pub fn iter_over_markets(market_json: &str) -> Result<Vec<FullMarket>, RowParsingError> {
+    let file_as_string = fs::read_to_string(market_json)
+        .map_err(|e| RowParsingError::Generic(format!("couldn't read {market_json}: {e}")))?;
+    let markets: Vec<FullMarket> = serde_json::from_str(&file_as_string)?;
+    Ok(markets)
 }
 
 pub fn create_market_table(conn: &Connection) -> Result<()> {
@@ -41,24 +44,86 @@ pub fn create_market_table(conn: &Connection) -> Result<()> {
             resolution TEXT,
             resolution_probability REAL,
             last_updated_time INTEGER,
-            last_bet_time INTEGER
+            last_bet_time INTEGER,
+            text_description TEXT,
+            group_slugs TEXT,
+            slug TEXT
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Full text search over the markets' questions and descriptions, for
+/// GET /v0/search-markets. Markets are found by `id`.
+pub fn create_market_search_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE VIRTUAL TABLE markets_fts USING fts5(
+            id UNINDEXED,
+            question,
+            text_description
         )",
         [],
     )?;
     Ok(())
 }
 
-pub fn bulk_insert_markets(conn: &mut Connection, markets: &Vec<LiteMarket>) -> Result<usize> {
+pub fn bulk_insert_market_search(conn: &mut Connection, markets: &[FullMarket]) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO markets_fts (id, question, text_description) VALUES (?1, ?2, ?3)",
+        )?;
+        for market in markets {
+            stmt.execute(params![
+                market.lite_market.id,
+                market.lite_market.question,
+                market.text_description,
+            ])?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(markets.len())
+}
+
+/// The dump has no slugs, but a market's url ends with its slug
+pub fn slug_from_url(url: &str) -> &str {
+    url.trim_end_matches('/').rsplit('/').next().unwrap_or(url)
+}
+
+/// Fills in the slugs of markets that don't have one yet
+fn backfill_slugs(conn: &mut Connection) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut count = 0;
+
+    {
+        let mut select = tx.prepare("SELECT id, url FROM markets WHERE slug IS NULL")?;
+        let mut update = tx.prepare("UPDATE markets SET slug = ?1 WHERE id = ?2")?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: String = row.get(0)?;
+            let url: String = row.get(1)?;
+            count += update.execute(params![slug_from_url(&url), id])?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(count)
+}
+
+pub fn bulk_insert_markets(conn: &mut Connection, markets: &[LiteMarket]) -> Result<usize> {
     let stmt_str = "INSERT INTO markets (
         id, creator_id, creator_username, creator_name, creator_avatar_url, close_time,
         created_time, question, url, outcome_type, mechanism, probability,
         pool, p, total_liquidity, value, min, max, is_log_scale, volume,
         volume_24_hours, is_resolved, resolution_time, resolution,
-        resolution_probability, last_updated_time, last_bet_time
+        resolution_probability, last_updated_time, last_bet_time, slug
     ) VALUES (
         ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11,
         ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19,
-        ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27
+        ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28
     );";
 
     for chunk in markets.chunks(1000) {
@@ -95,7 +160,8 @@ pub fn bulk_insert_markets(conn: &mut Connection, markets: &Vec<LiteMarket>) ->
                     market.resolution,
                     market.resolution_probability,
                     market.last_updated_time,
-                    market.last_bet_time
+                    market.last_bet_time,
+                    slug_from_url(&market.url)
                 ])?;
             }
         }
@@ -106,6 +172,117 @@ pub fn bulk_insert_markets(conn: &mut Connection, markets: &Vec<LiteMarket>) ->
     Ok(markets.len())
 }
 
+/// The markets table as it looked at the simulated time `:as_of`. Markets created
+/// after `:as_of` are left out, and the fields that change over a market's life
+/// are rolled back using the bets table. The pool, p and total liquidity can't be
+/// rolled back, so they're hidden. Close times are the ones at the end of the
+/// data, since there's no record of them being changed. With a NULL `:as_of`,
+/// this is just the markets table.
+/// Has the LiteMarket columns of the markets table, so rows work with rusqlite_row_to_litemarket.
+pub const MARKETS_AS_OF: &str = "
+    SELECT
+      m.id, m.creator_id, m.creator_username, m.creator_name, m.creator_avatar_url,
+      m.close_time, m.created_time, m.question, m.url, m.outcome_type, m.mechanism,
+      CASE
+        WHEN :as_of IS NULL OR m.probability IS NULL THEN m.probability
+        ELSE COALESCE(
+          (SELECT b.prob_after FROM bets b
+            WHERE b.contract_id = m.id AND b.answer_id IS NULL AND b.created_time <= :as_of
+            ORDER BY b.created_time DESC LIMIT 1),
+          (SELECT b.prob_before FROM bets b
+            WHERE b.contract_id = m.id AND b.answer_id IS NULL
+            ORDER BY b.created_time ASC LIMIT 1),
+          m.probability)
+      END AS probability,
+      CASE WHEN :as_of IS NULL THEN m.pool ELSE 'null' END AS pool,
+      CASE WHEN :as_of IS NULL THEN m.p ELSE NULL END AS p,
+      CASE WHEN :as_of IS NULL THEN m.total_liquidity ELSE NULL END AS total_liquidity,
+      CASE WHEN :as_of IS NULL THEN m.value ELSE NULL END AS value,
+      m.min, m.max, m.is_log_scale,
+      CASE
+        WHEN :as_of IS NULL THEN m.volume
+        ELSE (SELECT COALESCE(SUM(ABS(b.amount)), 0) FROM bets b
+          WHERE b.contract_id = m.id AND b.created_time <= :as_of)
+      END AS volume,
+      CASE
+        WHEN :as_of IS NULL THEN m.volume_24_hours
+        ELSE (SELECT COALESCE(SUM(ABS(b.amount)), 0) FROM bets b
+          WHERE b.contract_id = m.id AND b.created_time <= :as_of
+            AND b.created_time > :as_of - 86400000)
+      END AS volume_24_hours,
+      CASE WHEN :as_of IS NULL OR m.resolution_time <= :as_of THEN m.is_resolved ELSE 0 END
+        AS is_resolved,
+      CASE WHEN :as_of IS NULL OR m.resolution_time <= :as_of THEN m.resolution_time END
+        AS resolution_time,
+      CASE WHEN :as_of IS NULL OR m.resolution_time <= :as_of THEN m.resolution END
+        AS resolution,
+      CASE WHEN :as_of IS NULL OR m.resolution_time <= :as_of THEN m.resolution_probability END
+        AS resolution_probability,
+      CASE
+        WHEN :as_of IS NULL THEN m.last_updated_time
+        ELSE COALESCE(
+          (SELECT MAX(b.created_time) FROM bets b
+            WHERE b.contract_id = m.id AND b.created_time <= :as_of),
+          m.created_time)
+      END AS last_updated_time,
+      CASE
+        WHEN :as_of IS NULL THEN m.last_bet_time
+        ELSE (SELECT MAX(b.created_time) FROM bets b
+          WHERE b.contract_id = m.id AND b.created_time <= :as_of)
+      END AS last_bet_time
+    FROM markets m
+    WHERE :as_of IS NULL OR m.created_time <= :as_of";
+
+/// Fills in the FullMarket fields that aren't in bulk_insert_markets
+pub fn bulk_update_full_market_fields(
+    conn: &mut Connection,
+    markets: &[FullMarket],
+) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    {
+        let mut stmt =
+            tx.prepare("UPDATE markets SET text_description = ?2, group_slugs = ?3 WHERE id = ?1")?;
+        for market in markets {
+            stmt.execute(params![
+                market.lite_market.id,
+                market.text_description,
+                serde_json::to_string(&market.group_slugs).unwrap(),
+            ])?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(markets.len())
+}
+
+/// MARKETS_AS_OF, with the FullMarket fields after the LiteMarket ones, for
+/// rusqlite_row_to_fullmarket. The answers are read separately.
+pub fn full_markets_as_of_query() -> String {
+    format!(
+        "SELECT m.*, f.text_description, f.group_slugs
+        FROM ({MARKETS_AS_OF}) m JOIN markets f ON f.id = m.id"
+    )
+}
+
+/// Attempts to convert a row of full_markets_as_of_query into a FullMarket,
+/// without its answers
+pub fn rusqlite_row_to_fullmarket(row: &Row) -> Result<FullMarket, RowParsingError> {
+    let group_slugs_str: Option<String> = row.get(28)?;
+    let group_slugs = match group_slugs_str {
+        Some(group_slugs_str) => serde_json::from_str::<Option<Vec<String>>>(&group_slugs_str)?,
+        None => None,
+    };
+
+    Ok(FullMarket {
+        lite_market: rusqlite_row_to_litemarket(row)?,
+        answers: None,
+        description: None,
+        text_description: row.get(27)?,
+        group_slugs,
+    })
+}
+
 /// Attempts to convert row into a LiteMarket.
 /// If there's the wrong number of rows, we return an Err.
 /// Sort-of an inverse of bulk_insert_markets
@@ -116,7 +293,11 @@ pub fn rusqlite_row_to_litemarket(row: &Row) -> Result<LiteMarket, RowParsingErr
 
     let outcome_type = serde_json::from_str::<MarketOutcomeType>(&outcome_str)?;
     let mechanism = serde_json::from_str::<MarketMechanism>(&mechanism_str)?;
-    let pool = Some(serde_json::from_str::<HashMap<String, f64>>(&pool_str)?);
+    let pool = if pool_str == "null" {
+        None
+    } else {
+        Some(serde_json::from_str::<HashMap<String, f64>>(&pool_str)?)
+    };
 
     Ok(LiteMarket {
         id: row.get(0)?,
@@ -149,7 +330,7 @@ pub fn rusqlite_row_to_litemarket(row: &Row) -> Result<LiteMarket, RowParsingErr
     })
 }
 
-pub fn init_market_table(conn: &mut Connection) -> Result<usize> {
+pub fn init_market_table(conn: &mut Connection) -> Result<usize, RowParsingError> {
     if !db_common::table_exists(conn, "markets")? {
         debug!("creating 'markets' table");
         create_market_table(conn)?;
@@ -161,13 +342,11 @@ pub fn init_market_table(conn: &mut Connection) -> Result<usize> {
 
     // TODO really we should check that the number of rows equals the number of bets,
     // or maybe just check if all the ids are in the db and insert the missing ones?
-    let num_rows =
-        db_common::count_rows(conn, "markets").expect("failed to count rows in markets table");
-    if db_common::count_rows(conn, "markets").expect("failed to count rows in markets table") == 0 {
+    let num_rows = db_common::count_rows(conn, "markets")?;
+    if num_rows == 0 {
         debug!("inserting markets...");
 
-        let markets =
-            iter_over_markets(&"backtest-data/manifold-dump-markets-04082023.json".to_string());
+        let markets = iter_over_markets(MARKETS_JSON)?;
 
         // Pull the lite markets out, because I don't want to deal w/ full market for now. Changing this
         // will be easy once we actually need the full markets!
@@ -182,6 +361,36 @@ pub fn init_market_table(conn: &mut Connection) -> Result<usize> {
         debug!("there are {num_rows} (instead of 0) rows, so not inserting anything");
     }
 
+    // markets tables from before the FullMarket fields were kept
+    let added_text_description =
+        db_common::add_column_if_missing(conn, "markets", "text_description", "TEXT")?;
+    let added_group_slugs =
+        db_common::add_column_if_missing(conn, "markets", "group_slugs", "TEXT")?;
+    if count > 0 || added_text_description || added_group_slugs {
+        let markets = iter_over_markets(MARKETS_JSON)?;
+        bulk_update_full_market_fields(conn, &markets)?;
+        debug!("full market fields filled in");
+    }
+
+    // markets tables from before slugs were stored
+    if db_common::add_column_if_missing(conn, "markets", "slug", "TEXT")? {
+        let backfilled = backfill_slugs(conn)?;
+        debug!("{backfilled} market slugs filled in");
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS markets_slug_index ON markets (slug);",
+        [],
+    )?;
+
+    if !db_common::table_exists(conn, "markets_fts")? {
+        debug!("creating 'markets_fts' table");
+        create_market_search_table(conn)?;
+
+        let markets = iter_over_markets(MARKETS_JSON)?;
+        let indexed = bulk_insert_market_search(conn, &markets)?;
+        debug!("{indexed} markets indexed for search");
+    }
+
     let start = std::time::Instant::now();
     conn.execute(
         "CREATE INDEX IF NOT EXISTS markets_index ON markets (created_time);",
@@ -192,5 +401,15 @@ pub fn init_market_table(conn: &mut Connection) -> Result<usize> {
         start.elapsed()
     );
 
+    // for finding the next event, see next_event_time
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS markets_close_index ON markets (close_time);",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS markets_resolution_index ON markets (resolution_time);",
+        [],
+    )?;
+
     Ok(count)
 }
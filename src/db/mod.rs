@@ -1,250 +1,1776 @@
+mod answer_table;
+mod api_key_table;
 mod bet_table;
 pub mod db_common;
 mod errors;
+mod events;
+mod group_table;
+mod historical_user_table;
+mod leaderboard;
 mod market_table;
+mod profit;
+mod session_table;
+mod sim_bet_table;
+mod simulation;
+mod snapshot;
+#[cfg(test)]
+pub mod test_fixture;
 mod user_table;
+mod wakeup_table;
+mod webhook_table;
 
-use rusqlite::{named_params, Connection, Result};
-use serde_json::Value;
+use rusqlite::{named_params, params, Connection, Result, Transaction, TransactionBehavior};
+use std::path::Path;
 
-use crate::db::bet_table::rusqlite_row_to_bet;
-use crate::db::errors::RowParsingError;
-use crate::db::market_table::rusqlite_row_to_litemarket;
-use crate::db::user_table::{rusqlite_row_to_user, DEFAULT_USER_ID};
+use crate::data_types::{
+    AnyUser, Bet, BetQuery, BetRequest, ClockMode, ContractMetric, FullMarket, HistoricalGroup,
+    HistoricalUser, LiteMarket, MarketOutcomeType, MarketQuery, SearchMarketsQuery, Session, User,
+    Wakeup, Webhook, WebhookEvent,
+};
+use crate::db::answer_table::get_answers_as_of;
+use crate::db::api_key_table::{get_user_id_for_api_key, insert_api_key};
+use crate::db::bet_table::{bet_as_of, rusqlite_row_to_bet};
+use crate::db::db_common::{new_id, now_millis};
+pub use crate::db::errors::RowParsingError;
+pub use crate::db::events::{
+    event_cursor, get_events_between, get_events_since, EventCursor, EventFilter, Markets,
+    SessionEvent,
+};
+use crate::db::group_table::{rusqlite_row_to_group, GROUPS_AS_OF};
+use crate::db::historical_user_table::{rusqlite_row_to_historical_user, HISTORICAL_USERS_AS_OF};
+pub use crate::db::leaderboard::get_leaderboard;
+use crate::db::market_table::{
+    full_markets_as_of_query, rusqlite_row_to_fullmarket, rusqlite_row_to_litemarket, MARKETS_AS_OF,
+};
+pub use crate::db::session_table::get_session_ids_with_clock_mode;
+pub use crate::db::session_table::DEFAULT_SESSION_ID;
+use crate::db::session_table::{anchor_clock_mode, insert_session, set_session_clock_mode};
+use crate::db::sim_bet_table::bets_as_of_query;
+pub use crate::db::snapshot::DEFAULT_SNAPSHOT_DIR;
+pub use crate::db::user_table::DEFAULT_STARTING_BALANCE;
+
+use crate::db::user_table::{
+    get_new_backtest_user, insert_user, rusqlite_row_to_user, DEFAULT_USER_ID,
+};
+use crate::db::wakeup_table::insert_wakeup;
+use crate::db::webhook_table::insert_webhook;
+
+/// The SQL direction for Manifold's `order` parameter, which is descending
+/// unless it's given
+fn parse_order(order: Option<&str>) -> Result<&'static str, RowParsingError> {
+    match order {
+        Some("asc") => Ok("ASC"),
+        None | Some("desc") => Ok("DESC"),
+        Some(order) => Err(RowParsingError::InvalidRequest(format!(
+            "invalid order '{order}', expected asc or desc"
+        ))),
+    }
+}
+
+/// Manifold's `limit` parameter, which has to be between 0 and 1000 and is
+/// 500 if it's not given
+fn check_limit(limit: Option<i64>) -> Result<i64, RowParsingError> {
+    match limit {
+        None => Ok(500),
+        Some(limit) if (0..=1000).contains(&limit) => Ok(limit),
+        Some(limit) => Err(RowParsingError::InvalidRequest(format!(
+            "invalid limit {limit}, expected a number from 0 to 1000"
+        ))),
+    }
+}
 
 /// Impls GET /v0/markets
 /// Note that we filter the column 'creator_id' by
 /// the query parameter 'user_id'.
-/// Also note that groupId is not in the backtest data,
-/// so it's ignored here.
+/// Also note that groups are only known by their slugs,
+/// so groupId is a group slug, see group_table.
 /// Also, sort can't have the value last-comment-time because
 /// there isn't a column for that in the backtest data.
-fn get_markets(
+/// Only markets that exist at the session clock are returned.
+pub fn get_markets(
     conn: &Connection,
-    id: Option<&str>,
-    limit: Option<i64>,
-    sort: Option<&str>,
-    order: Option<&str>,
-    before: Option<&str>,
-    user_id: Option<&str>,
-) -> Result<Vec<Value>, RowParsingError> {
-    let order = match order {
-        Some("desc") => "DESC",
-        _ => "ASC",
-    };
+    session: &Session,
+    query: &MarketQuery,
+) -> Result<Vec<LiteMarket>, RowParsingError> {
+    let order = parse_order(query.order.as_deref())?;
+    let limit = check_limit(query.limit)?;
 
-    let sort = match sort {
-        Some("created-time") => "created-time",
+    let sort = match query.sort.as_deref() {
+        None | Some("created-time") => "created-time",
         Some("updated-time") => "updated-time",
         Some("last-bet-time") => "last-bet-time",
-        _ => "created-time",
+        Some("volume") => "volume",
+        Some("close-time") => "close-time",
+        // liquidity is only known at the end of the data, which is where the
+        // default session is
+        Some("total-liquidity") if session.clock_time.is_none() => "total-liquidity",
+        Some("total-liquidity") => {
+            return Err(RowParsingError::InvalidRequest(
+                "sort total-liquidity is only supported in the default session".to_string(),
+            ))
+        }
+        // the data has no comments
+        Some("last-comment-time") => {
+            return Err(RowParsingError::InvalidRequest(
+                "sort last-comment-time is not supported in backtest".to_string(),
+            ))
+        }
+        Some(sort) => {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "invalid sort '{sort}', expected one of created-time, updated-time, \
+                last-bet-time, volume, close-time, total-liquidity"
+            )))
+        }
     };
 
-    let query = format!(
-        "SELECT * FROM markets
+    // `before` is a cursor: the page starts after that market, in the sort
+    // order, with ties broken by id so that every market is on exactly one page
+    let after_cursor = if order == "DESC" { "<" } else { ">" };
+
+    if let Some(before) = &query.before {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT 1 FROM ({MARKETS_AS_OF}) WHERE id = :before"
+        ))?;
+        if !stmt.exists(named_params! { ":as_of": session.clock_time, ":before": before })? {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "no market with id {before} to page from"
+            )));
+        }
+    }
+
+    let sql = format!(
+        "WITH sorted_markets AS (
+          SELECT *,
+            COALESCE(CASE
+              WHEN :sort = 'created-time' THEN created_time
+              WHEN :sort = 'updated-time' THEN last_updated_time
+              WHEN :sort = 'last-bet-time' THEN last_bet_time
+              WHEN :sort = 'volume' THEN volume
+              WHEN :sort = 'total-liquidity' THEN total_liquidity
+              -- markets that never close go after the ones that do
+              WHEN :sort = 'close-time' THEN COALESCE(close_time, 9223372036854775807)
+            END, 0) AS sort_key
+          FROM ({MARKETS_AS_OF})
+          -- a group's markets are picked out before rolling them back, so
+          -- group pages don't roll back every market
+          WHERE :group_id IS NULL OR
+            id IN (SELECT market_id FROM group_markets WHERE group_id = :group_id)
+        )
+        SELECT * FROM sorted_markets
         WHERE
-          (:id is NULL OR id = :id) AND
           (:user_id IS NULL OR creator_id = :user_id) AND
-          (:before IS NULL OR id < :before)
-        ORDER BY
-          CASE
-            WHEN :sort = 'created-time' THEN created_time
-            WHEN :sort = 'updated-time' THEN last_updated_time
-            WHEN :sort = 'last-bet-time' THEN last_bet_time
-          END {order}
+          (:outcome_type IS NULL OR outcome_type = :outcome_type) AND
+          (:mechanism IS NULL OR mechanism = :mechanism) AND
+          (:is_resolved IS NULL OR is_resolved = :is_resolved) AND
+          (:created_after IS NULL OR created_time > :created_after) AND
+          (:created_before IS NULL OR created_time < :created_before) AND
+          (:close_time_after IS NULL OR close_time > :close_time_after) AND
+          (:close_time_before IS NULL OR close_time < :close_time_before) AND
+          (:min_volume IS NULL OR volume >= :min_volume) AND
+          (:before IS NULL OR
+            (sort_key, id) {after_cursor} (SELECT sort_key, id FROM sorted_markets WHERE id = :before))
+        ORDER BY sort_key {order}, id {order}
         LIMIT :limit;"
     );
 
-    let mut stmt = conn.prepare(&query)?;
+    // outcome types and mechanisms are stored as json strings
+    let outcome_type = query
+        .outcome_type
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let mechanism = query
+        .mechanism
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+
+    let mut stmt = conn.prepare(&sql)?;
 
     let market_iter = stmt.query_map(
         named_params! {
-            ":id": id,
-            ":limit": limit.unwrap_or(500).min(1000),
+            ":as_of": session.clock_time,
+            ":limit": limit,
             ":sort": sort,
-            ":before": before,
-            ":user_id": user_id,
+            ":before": query.before,
+            ":user_id": query.user_id,
+            ":group_id": query.group_id,
+            ":outcome_type": outcome_type,
+            ":mechanism": mechanism,
+            ":is_resolved": query.is_resolved,
+            ":created_after": query.created_after,
+            ":created_before": query.created_before,
+            ":close_time_after": query.close_time_after,
+            ":close_time_before": query.close_time_before,
+            ":min_volume": query.min_volume,
         },
         |row| Ok(rusqlite_row_to_litemarket(row)),
     )?;
 
-    let mut markets: Vec<Value> = Vec::new();
+    let mut markets = Vec::new();
     for maybe_market in market_iter {
         // ??!! haha
-        let market = maybe_market??;
-        let market_json = serde_json::to_value(market)?;
-        markets.push(market_json);
+        markets.push(maybe_market??);
     }
 
     Ok(markets)
 }
 
-pub fn get_markets_by_params(
+/// Impls GET /v0/groups
+/// The groups that had a market by the session clock, newest first, and
+/// created before `before_time` if it's given
+pub fn get_groups(
     conn: &Connection,
-    limit: Option<i64>,
-    sort: Option<&str>,
-    order: Option<&str>,
-    before: Option<&str>,
-    user_id: Option<&str>,
-) -> Result<Vec<Value>, RowParsingError> {
-    get_markets(conn, None, limit, sort, order, before, user_id)
+    session: &Session,
+    before_time: Option<u64>,
+) -> Result<Vec<HistoricalGroup>, RowParsingError> {
+    let query = format!(
+        "SELECT * FROM ({GROUPS_AS_OF})
+        WHERE :before_time IS NULL OR created_time < :before_time
+        ORDER BY created_time DESC, id DESC;"
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+
+    let group_iter = stmt.query_map(
+        named_params! {
+            ":as_of": session.clock_time,
+            ":id": None::<&str>,
+            ":slug": None::<&str>,
+            ":before_time": before_time,
+        },
+        |row| Ok(rusqlite_row_to_group(row)),
+    )?;
+
+    let mut groups = Vec::new();
+    for maybe_group in group_iter {
+        groups.push(maybe_group??);
+    }
+
+    Ok(groups)
 }
 
-pub fn get_markets_by_id(
+/// Impls GET /v0/group/[slug] and GET /v0/group/by-id/[id], looking the group
+/// up by `id` if it's given, and `slug` otherwise
+pub fn get_group(
     conn: &Connection,
+    session: &Session,
     id: Option<&str>,
-) -> Result<Vec<Value>, RowParsingError> {
-    get_markets(conn, id, None, None, None, None, None)
+    slug: Option<&str>,
+) -> Result<HistoricalGroup, RowParsingError> {
+    let mut stmt = conn.prepare(GROUPS_AS_OF)?;
+
+    let mut group_iter = stmt.query_map(
+        named_params! {
+            ":as_of": session.clock_time,
+            ":id": id,
+            // the id wins if both are given
+            ":slug": if id.is_some() { None } else { slug },
+        },
+        |row| Ok(rusqlite_row_to_group(row)),
+    )?;
+
+    match group_iter.next() {
+        Some(group) => Ok(group??),
+        None => Err(RowParsingError::GroupNotFound(format!(
+            "no group with {}",
+            match id {
+                Some(id) => format!("id {id}"),
+                None => format!("slug {}", slug.unwrap_or_default()),
+            }
+        ))),
+    }
 }
 
-pub fn get_market_by_slug(conn: &Connection, slug: &str) -> Result<Vec<Value>, RowParsingError> {
-    let query = "SELECT * FROM markets WHERE url LIKE '%' || :slug || '%';";
+/// Impls GET /v0/group/by-id/[id]/markets
+pub fn get_group_markets(
+    conn: &Connection,
+    session: &Session,
+    id: &str,
+) -> Result<Vec<LiteMarket>, RowParsingError> {
+    // so that a missing group is an error rather than no markets
+    get_group(conn, session, Some(id), None)?;
 
-    let mut stmt = conn.prepare(query)?;
+    get_markets(
+        conn,
+        session,
+        &MarketQuery {
+            limit: Some(1000),
+            group_id: Some(id.to_string()),
+            ..Default::default()
+        },
+    )
+}
 
-    let market_iter = stmt.query_map(
+/// Impls GET /v0/market/[id]
+/// The FullMarket as of the session clock, with the answers that had been
+/// added by then. The rich text description isn't in the backtest data, so
+/// it's always null; textDescription has the text.
+pub fn get_full_market(
+    conn: &Connection,
+    session: &Session,
+    id: &str,
+) -> Result<FullMarket, RowParsingError> {
+    let query = format!(
+        "SELECT * FROM ({}) WHERE id = :id;",
+        full_markets_as_of_query()
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+
+    let mut market_iter = stmt.query_map(
         named_params! {
-            ":slug": slug,
+            ":as_of": session.clock_time,
+            ":id": id,
         },
-        |row| Ok(rusqlite_row_to_litemarket(row)),
+        |row| Ok(rusqlite_row_to_fullmarket(row)),
     )?;
 
-    let mut markets: Vec<Value> = Vec::new();
+    let mut market = match market_iter.next() {
+        Some(market) => market??,
+        None => {
+            return Err(RowParsingError::MarketNotFound(format!(
+                "no markets found for market id {id}"
+            )))
+        }
+    };
+    market.answers = get_answers_as_of(conn, id, session.clock_time)?;
+
+    Ok(market)
+}
+
+/// The id of the market with `slug` that exists at the session clock
+fn get_market_id_by_slug(
+    conn: &Connection,
+    session: &Session,
+    slug: &str,
+) -> Result<String, RowParsingError> {
+    let query = format!(
+        "SELECT id FROM ({MARKETS_AS_OF})
+        WHERE id IN (SELECT id FROM markets WHERE slug = :slug);"
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+
+    let ids = stmt
+        .query_map(
+            named_params! {
+                ":as_of": session.clock_time,
+                ":slug": slug,
+            },
+            |row| row.get(0),
+        )?
+        .collect::<Result<Vec<String>>>()?;
+
+    match ids.as_slice() {
+        [id] => Ok(id.clone()),
+        [] => Err(RowParsingError::MarketNotFound(format!(
+            "no markets found for slug {slug}"
+        ))),
+        _ => Err(RowParsingError::InvalidRequest(format!(
+            "more than one market found for slug {slug}"
+        ))),
+    }
+}
+
+/// Impls GET /v0/slug/[slug]
+pub fn get_market_by_slug(
+    conn: &Connection,
+    session: &Session,
+    slug: &str,
+) -> Result<FullMarket, RowParsingError> {
+    let id = get_market_id_by_slug(conn, session, slug)?;
+    get_full_market(conn, session, &id)
+}
+
+/// Turns a search term into an FTS5 query that matches markets with every
+/// word in it, the last one as a prefix, so punctuation in the term can't be
+/// read as FTS5 syntax
+fn fts_query(term: &str) -> Option<String> {
+    let words: Vec<String> = term
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(format!("{}*", words.join(" ")))
+    }
+}
+
+/// Impls GET /v0/search-markets
+/// Searches the questions and descriptions of the markets that exist at the
+/// session clock, with open, closed and resolved also as of the clock.
+/// Sorts that need data we don't have (like score) fall back to most-popular,
+/// which is by volume here.
+pub fn search_markets(
+    conn: &Connection,
+    session: &Session,
+    query: &SearchMarketsQuery,
+) -> Result<Vec<LiteMarket>, RowParsingError> {
+    let fts_term = query.term.as_deref().and_then(fts_query);
+
+    let (search_join, search_condition) = match fts_term {
+        Some(_) => (
+            "LEFT JOIN (
+              SELECT id, bm25(markets_fts) AS rank FROM markets_fts WHERE markets_fts MATCH :term
+            ) f ON f.id = m.id",
+            "f.id IS NOT NULL",
+        ),
+        None => ("LEFT JOIN (SELECT NULL AS id, NULL AS rank) f", "1"),
+    };
+
+    let filter_condition = match query.filter.as_deref() {
+        None | Some("all") => "1",
+        Some("open") => "NOT m.is_resolved AND (m.close_time IS NULL OR m.close_time > :now)",
+        Some("closed") => "NOT m.is_resolved AND m.close_time <= :now",
+        Some("resolved") => "m.is_resolved",
+        Some(filter) => {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "invalid filter '{filter}', expected one of all, open, closed, resolved"
+            )))
+        }
+    };
+
+    let order_by = match query.sort.as_deref() {
+        Some("relevance") if fts_term.is_some() => "f.rank ASC",
+        Some("newest") => "m.created_time DESC",
+        Some("24-hour-vol") => "m.volume_24_hours DESC",
+        Some("last-updated") => "m.last_updated_time DESC",
+        Some("close-date") => "m.close_time IS NULL, m.close_time ASC",
+        Some("resolve-date") => "m.resolution_time IS NULL, m.resolution_time DESC",
+        Some("prob-descending") => "m.probability DESC",
+        Some("prob-ascending") => "m.probability ASC",
+        Some("random") => "RANDOM()",
+        None | Some("most-popular" | "relevance" | "score" | "daily-score" | "freshness-score") => {
+            "m.volume DESC"
+        }
+        // see the total-liquidity sort in get_markets
+        Some("liquidity") if session.clock_time.is_none() => "m.total_liquidity DESC",
+        Some("liquidity") => {
+            return Err(RowParsingError::InvalidRequest(
+                "sort liquidity is only supported in the default session".to_string(),
+            ))
+        }
+        Some(sort) => {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "invalid sort '{sort}', expected one of most-popular, relevance, score, \
+                daily-score, freshness-score, newest, 24-hour-vol, last-updated, \
+                close-date, resolve-date, prob-descending, prob-ascending, liquidity, random"
+            )))
+        }
+    };
+
+    // anything but ALL has to be an outcome type
+    if let Some(contract_type) = query.contract_type.as_deref().filter(|t| *t != "ALL") {
+        serde_json::from_value::<MarketOutcomeType>(serde_json::json!(contract_type)).map_err(
+            |_| {
+                RowParsingError::InvalidRequest(format!(
+                    "invalid contractType '{contract_type}', expected ALL or an outcome type"
+                ))
+            },
+        )?;
+    }
+
+    let limit = match query.limit {
+        None => 100,
+        limit => check_limit(limit)?,
+    };
+    let offset = query.offset.unwrap_or(0);
+    if offset < 0 {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "invalid offset {offset}, expected a number from 0"
+        )));
+    }
+
+    let sql = format!(
+        "SELECT m.* FROM ({MARKETS_AS_OF}) m
+        {search_join}
+        WHERE
+          {search_condition} AND
+          {filter_condition} AND
+          (:contract_type IS NULL OR :contract_type = 'ALL' OR
+            m.outcome_type = '\"' || :contract_type || '\"')
+        ORDER BY {order_by}, m.id
+        LIMIT :limit OFFSET :offset;"
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+
+    let now = session.clock_time.unwrap_or_else(now_millis);
+    let mut params = named_params! {
+        ":as_of": session.clock_time,
+        ":contract_type": query.contract_type,
+        ":limit": limit,
+        ":offset": offset,
+    }
+    .to_vec();
+    // rusqlite won't bind parameters the query doesn't use
+    if filter_condition.contains(":now") {
+        params.push((":now", &now as &dyn rusqlite::ToSql));
+    }
+    if let Some(fts_term) = &fts_term {
+        params.push((":term", fts_term as &dyn rusqlite::ToSql));
+    }
+
+    let market_iter =
+        stmt.query_map(params.as_slice(), |row| Ok(rusqlite_row_to_litemarket(row)))?;
+
+    let mut markets = Vec::new();
     for maybe_market in market_iter {
-        // ??!! haha
-        let market = maybe_market??;
-        let market_json = serde_json::to_value(market)?;
-        markets.push(market_json);
+        markets.push(maybe_market??);
     }
 
     Ok(markets)
 }
 
+/// Impls GET /v0/bets
+/// Returns the historical bets up to the session clock, along with
+/// the session's simulated bets.
 pub fn get_bets(
     conn: &Connection,
-    user_id: Option<&str>,
-    username: Option<&str>,
-    contract_id: Option<&str>,
-    contract_slug: Option<&str>,
-    limit: Option<i64>,
-    before: Option<&str>,
-    after: Option<&str>,
-    order: Option<&str>,
-) -> Result<Vec<Value>, RowParsingError> {
-    if let Some(contract_slug) = contract_slug {
-        let markets = get_market_by_slug(conn, contract_slug)?;
-
-        if markets.is_empty() {
-            return Err(RowParsingError::MarketNotFound(
-                format!("could not find market with slug {contract_slug}").to_string(),
-            ));
-        } else if markets.len() > 1 {
-            return Err(RowParsingError::MarketNotFound(
-                format!("found multiple markets with slug {contract_slug}").to_string(),
+    session: &Session,
+    query: &BetQuery,
+) -> Result<Vec<Bet>, RowParsingError> {
+    let mut contract_id = query.contract_id.clone();
+    if let Some(contract_slug) = &query.contract_slug {
+        let market_id_from_slug = get_market_id_by_slug(conn, session, contract_slug)?;
+
+        if contract_id.is_some() && contract_id.as_ref() != Some(&market_id_from_slug) {
+            return Err(RowParsingError::InvalidRequest(
+                "provided contract id does not match slug".to_string(),
             ));
-        } else {
-            let market = markets.first().unwrap();
-            let market_id_from_slug = Some(market.get("id").unwrap().as_str().unwrap());
-
-            if contract_id.is_some() && contract_id != market_id_from_slug {
-                return Err(RowParsingError::MarketNotFound(
-                    "provided contract id does not match slug".to_string(),
-                ));
-            }
-            return get_bets_by_params(
-                conn,
-                user_id,
-                username,
-                market_id_from_slug,
-                None,
-                limit,
-                before,
-                after,
-                order,
-            );
         }
+        contract_id = Some(market_id_from_slug);
     }
 
-    let order = match order {
-        Some("desc") => "DESC",
-        _ => "ASC",
+    let order = parse_order(query.order.as_deref())?;
+    let limit = check_limit(query.limit)?;
+
+    // Manifold's only kind is open limit orders. As of the session clock, an
+    // order is open until it expires, or until the fills so far complete it,
+    // which only happens with its last fill. There's no record of when the
+    // historical orders were cancelled, so those count as open throughout.
+    let open_limit_only = match query.kinds.as_deref() {
+        None => false,
+        Some("open-limit") => true,
+        Some(kinds) => {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "invalid kinds '{kinds}', expected open-limit"
+            )))
+        }
     };
 
-    let query = format!(
-        "SELECT * FROM bets
+    // `before` and `after` are cursors, like the markets' `before`: the page
+    // after that bet in the order, or the page before it, with ties broken by
+    // id so that every bet is on exactly one page. The page before is the bets
+    // right up to the cursor, so it's read back from the cursor and reversed.
+    let (after_cursor, before_cursor) = if order == "DESC" {
+        ("<", ">")
+    } else {
+        (">", "<")
+    };
+    let read_backwards = query.after.is_some() && query.before.is_none();
+    let read_order = match (read_backwards, order) {
+        (true, "DESC") => "ASC",
+        (true, _) => "DESC",
+        (false, order) => order,
+    };
+
+    let sql = format!(
+        "WITH visible_bets AS ({})
+        SELECT * FROM visible_bets
         WHERE
           (:user_id IS NULL OR user_id = :user_id) AND
-          (:username IS NULL OR user_name = :username) AND
+          (:username IS NULL OR user_username = :username) AND
           (:contract_id IS NULL OR contract_id = :contract_id) AND
-          (:before IS NULL OR created_time < (SELECT created_time FROM bets WHERE id = :before)) AND
-          (:after IS NULL OR created_time > (SELECT created_time FROM bets WHERE id = :before))
-        ORDER BY created_time {order}
-        LIMIT :limit;"
+          (NOT :open_limit_only OR (
+            limit_props != 'null' AND
+            NOT (json_extract(limit_props, '$.isFilled') AND (:as_of IS NULL OR COALESCE(
+              (SELECT MAX(json_extract(value, '$.timestamp'))
+                FROM json_each(limit_props, '$.fills')),
+              created_time) <= :as_of)) AND
+            NOT (json_extract(limit_props, '$.isCancelled') AND (:as_of IS NULL OR
+              id IN (SELECT id FROM sim_bets WHERE session_id = :session_id))) AND
+            (:as_of IS NULL OR COALESCE(json_extract(limit_props, '$.expiresAt') > :as_of, 1)))) AND
+          (NOT :filter_redemptions OR NOT is_redemption) AND
+          (:answer_id IS NULL OR answer_id = :answer_id) AND
+          (:min_amount IS NULL OR ABS(amount) >= :min_amount) AND
+          (NOT :exclude_antes OR NOT is_ante) AND
+          (:after_time IS NULL OR created_time > :after_time) AND
+          (:before_time IS NULL OR created_time < :before_time) AND
+          (:before IS NULL OR
+            (created_time, id) {after_cursor} (SELECT created_time, id FROM visible_bets WHERE id = :before)) AND
+          (:after IS NULL OR
+            (created_time, id) {before_cursor} (SELECT created_time, id FROM visible_bets WHERE id = :after))
+        ORDER BY created_time {read_order}, id {read_order}
+        LIMIT :limit;",
+        bets_as_of_query()
     );
 
-    let mut stmt = conn.prepare(&query)?;
+    // a cursor has to be a bet in the session, or there's nothing to page from
+    for cursor in [&query.before, &query.after].into_iter().flatten() {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT 1 FROM ({}) WHERE id = :cursor",
+            bets_as_of_query()
+        ))?;
+        let params = named_params! {
+            ":as_of": session.clock_time,
+            ":session_id": session.id,
+            ":cursor": cursor,
+        };
+        if !stmt.exists(params)? {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "no bet with id {cursor} to page from"
+            )));
+        }
+    }
+
+    let mut stmt = conn.prepare(&sql)?;
 
     let bet_iter = stmt.query_map(
         named_params! {
-            ":user_id": user_id,
-            ":username": username,
+            ":as_of": session.clock_time,
+            ":session_id": session.id,
+            ":user_id": query.user_id,
+            ":username": query.username,
             ":contract_id": contract_id,
-            ":limit": limit.unwrap_or(500).min(1000),
-            ":before": before,
-            ":after": after,
+            ":limit": limit,
+            ":before": query.before,
+            ":after": query.after,
+            ":open_limit_only": open_limit_only,
+            ":filter_redemptions": query.filter_redemptions.unwrap_or(false),
+            ":answer_id": query.answer_id,
+            ":min_amount": query.min_amount,
+            ":exclude_antes": query.exclude_antes.unwrap_or(false),
+            ":after_time": query.after_time,
+            ":before_time": query.before_time,
         },
         |row| Ok(rusqlite_row_to_bet(row)),
     )?;
 
-    let mut bets: Vec<Value> = Vec::new();
+    let mut bets = Vec::new();
     for maybe_bet in bet_iter {
         // ??!! haha
-        let bet = maybe_bet??;
-        let bet_json = serde_json::to_value(bet)?;
-        bets.push(bet_json);
+        bets.push(bet_as_of(maybe_bet??, session.clock_time));
+    }
+    if read_backwards {
+        bets.reverse();
     }
 
     Ok(bets)
 }
 
-pub fn get_bets_by_params(
+/// Finds the simulated user behind an api key. The first time a key is seen,
+/// a new user is created for it with `starting_balance` mana.
+pub fn get_or_create_user_id_for_api_key(
     conn: &Connection,
-    user_id: Option<&str>,
-    username: Option<&str>,
-    contract_id: Option<&str>,
-    contract_slug: Option<&str>,
-    limit: Option<i64>,
-    before: Option<&str>,
-    after: Option<&str>,
-    order: Option<&str>,
-) -> Result<Vec<Value>, RowParsingError> {
-    get_bets(
-        conn,
-        user_id,
-        username,
-        contract_id,
-        contract_slug,
-        limit,
-        before,
-        after,
-        order,
-    )
+    session: &Session,
+    api_key: &str,
+    starting_balance: f64,
+) -> Result<String, RowParsingError> {
+    // immediate, so two requests racing with the same new key can't both create a user
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+
+    if let Some(user_id) = get_user_id_for_api_key(&tx, &session.id, api_key)? {
+        return Ok(user_id);
+    }
+
+    let user_id = new_id();
+    let username = format!("backtest-bot-{}", &user_id[..8]);
+
+    insert_user(
+        &tx,
+        get_new_backtest_user(
+            &user_id,
+            &username,
+            starting_balance,
+            session.clock_time.unwrap_or_else(now_millis),
+        ),
+    )?;
+    insert_api_key(&tx, &session.id, api_key, &user_id)?;
+    tx.commit()?;
+
+    log::info!(
+        "created user {username} for a new api key in session {}",
+        session.id
+    );
+
+    Ok(user_id)
 }
 
-pub fn get_me(conn: &Connection) -> Result<Value, RowParsingError> {
-    let query = "SELECT * FROM users WHERE id = :default_user_id LIMIT 1;";
+/// The simulated user making a request. Without an api key, this is the
+/// default user, which only exists in the default session.
+fn get_request_user_id(
+    conn: &Connection,
+    session: &Session,
+    api_key: Option<&str>,
+    starting_balance: f64,
+) -> Result<String, RowParsingError> {
+    match api_key {
+        Some(api_key) => {
+            get_or_create_user_id_for_api_key(conn, session, api_key, starting_balance)
+        }
+        None if session.id == DEFAULT_SESSION_ID => Ok(DEFAULT_USER_ID.to_string()),
+        None => Err(RowParsingError::InvalidRequest(format!(
+            "session {} needs an api key",
+            session.id
+        ))),
+    }
+}
+
+/// Impls GET /v0/me
+/// Without an api key, this is the default user. Otherwise it's the
+/// simulated user that belongs to the key.
+pub fn get_me(
+    conn: &Connection,
+    session: &Session,
+    api_key: Option<&str>,
+    starting_balance: f64,
+) -> Result<User, RowParsingError> {
+    let user_id = get_request_user_id(conn, session, api_key, starting_balance)?;
+
+    let query = "SELECT * FROM users WHERE id = :user_id LIMIT 1;";
 
     let mut stmt = conn.prepare(query)?;
 
     let mut user_iter = stmt.query_map(
         named_params! {
+            ":user_id": user_id,
+        },
+        |row| Ok(rusqlite_row_to_user(row)),
+    )?;
+
+    let mut user = match user_iter.next() {
+        Some(user) => user??,
+        None => {
+            return Err(RowParsingError::UserNotFound(format!(
+                "no user with id {user_id}"
+            )))
+        }
+    };
+    user.profit_cached =
+        profit::get_profit_cached(conn, Some(&session.id), &user_id, session.clock_time)?;
+
+    Ok(user)
+}
+
+/// Impls GET /v0/user/[username] and GET /v0/user/by-id/[id], looking the user
+/// up by `id` if it's given, and `username` otherwise.
+/// The session's simulated users come first, then the historical users who had
+/// shown up by the session clock.
+pub fn get_user(
+    conn: &Connection,
+    session: &Session,
+    id: Option<&str>,
+    username: Option<&str>,
+) -> Result<AnyUser, RowParsingError> {
+    let simulated_query = "
+        SELECT users.* FROM users
+        LEFT JOIN api_keys ON api_keys.user_id = users.id
+        WHERE
+          (api_keys.session_id = :session_id
+            OR (users.id = :default_user_id AND :session_id = :default_session_id))
+          AND (users.id = :id OR (:id IS NULL AND users.username = :username))
+        LIMIT 1;";
+
+    let mut stmt = conn.prepare(simulated_query)?;
+    let mut user_iter = stmt.query_map(
+        named_params! {
+            ":session_id": session.id,
             ":default_user_id": DEFAULT_USER_ID,
+            ":default_session_id": DEFAULT_SESSION_ID,
+            ":id": id,
+            ":username": username,
         },
         |row| Ok(rusqlite_row_to_user(row)),
     )?;
+    if let Some(user) = user_iter.next() {
+        let mut user = user??;
+        user.profit_cached =
+            profit::get_profit_cached(conn, Some(&session.id), &user.id, session.clock_time)?;
+        return Ok(AnyUser::Simulated(user));
+    }
+
+    let historical_query = format!(
+        "SELECT * FROM ({HISTORICAL_USERS_AS_OF})
+        WHERE id = :id OR (:id IS NULL AND username = :username)
+        ORDER BY created_time ASC
+        LIMIT 1;"
+    );
+
+    let mut stmt = conn.prepare(&historical_query)?;
+    let mut user_iter = stmt.query_map(
+        named_params! {
+            ":as_of": session.clock_time,
+            ":id": id,
+            ":username": username,
+        },
+        |row| Ok(rusqlite_row_to_historical_user(row)),
+    )?;
+    match user_iter.next() {
+        Some(user) => {
+            let mut user = user??;
+            user.user.profit_cached =
+                profit::get_profit_cached(conn, None, &user.user.id, session.clock_time)?;
+            Ok(AnyUser::Historical(user))
+        }
+        None => Err(RowParsingError::UserNotFound(format!(
+            "no user {}",
+            id.or(username).unwrap_or_default()
+        ))),
+    }
+}
+
+/// Impls GET /v0/users
+/// Lists the historical users who had shown up by the session clock, newest
+/// first, like Manifold does. `before` is the id of the user to start after.
+pub fn get_users(
+    conn: &Connection,
+    session: &Session,
+    limit: Option<i64>,
+    before: Option<&str>,
+) -> Result<Vec<HistoricalUser>, RowParsingError> {
+    // `before` is a cursor, like the markets' one, so it has to be a user the
+    // session can see
+    if let Some(before) = before {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT 1 FROM ({HISTORICAL_USERS_AS_OF}) WHERE id = :before"
+        ))?;
+        if !stmt.exists(named_params! { ":as_of": session.clock_time, ":before": before })? {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "no user with id {before} to page from"
+            )));
+        }
+    }
+
+    let query = format!(
+        "WITH visible_users AS ({HISTORICAL_USERS_AS_OF})
+        SELECT * FROM visible_users
+        WHERE
+          :before IS NULL OR
+          (created_time, id) < (SELECT created_time, id FROM visible_users WHERE id = :before)
+        ORDER BY created_time DESC, id DESC
+        LIMIT :limit;"
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+
+    let user_iter = stmt.query_map(
+        named_params! {
+            ":as_of": session.clock_time,
+            ":limit": check_limit(limit)?,
+            ":before": before,
+        },
+        |row| Ok(rusqlite_row_to_historical_user(row)),
+    )?;
 
-    let user = user_iter.next().expect("default user missing")??;
-    let user_json = serde_json::to_value(user)?;
+    let mut users = Vec::new();
+    for maybe_user in user_iter {
+        users.push(maybe_user??);
+    }
+
+    let user_ids: Vec<String> = users.iter().map(|user| user.user.id.clone()).collect();
+    let mut profits = profit::get_profits_cached(conn, None, &user_ids, session.clock_time)?;
+    for user in &mut users {
+        user.user.profit_cached = profits.remove(&user.user.id).unwrap_or_default();
+    }
+
+    Ok(users)
+}
+
+pub fn get_session(conn: &Connection, id: &str) -> Result<Session, RowParsingError> {
+    session_table::get_session(conn, id)?
+        .ok_or_else(|| RowParsingError::SessionNotFound(format!("no session with id {id}")))
+}
+
+/// Creates a session with its clock at `start_time`, or at the
+/// creation of the first market if no start time is given
+pub fn create_session(
+    conn: &Connection,
+    start_time: Option<u64>,
+    clock_mode: ClockMode,
+) -> Result<Session, RowParsingError> {
+    let clock_time = match start_time {
+        Some(start_time) => start_time,
+        None => conn
+            .query_row("SELECT MIN(created_time) FROM markets", [], |row| {
+                row.get::<_, Option<u64>>(0)
+            })?
+            .unwrap_or(0),
+    };
+
+    validate_clock_mode(&clock_mode)?;
+
+    let session = Session {
+        id: new_id(),
+        created_time: now_millis(),
+        clock_time: Some(clock_time),
+        clock_mode: anchor_clock_mode(clock_mode, Some(clock_time)),
+    };
+    insert_session(conn, &session)?;
+
+    log::info!("created session {} at {clock_time}", session.id);
+
+    Ok(session)
+}
+
+/// Deletes a session, along with its simulated users and bets
+pub fn delete_session(conn: &Connection, id: &str) -> Result<(), RowParsingError> {
+    if id == DEFAULT_SESSION_ID {
+        return Err(RowParsingError::InvalidRequest(
+            "the default session can't be deleted".to_string(),
+        ));
+    }
+    get_session(conn, id)?;
+
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    tx.execute(
+        "DELETE FROM users WHERE id IN (SELECT user_id FROM api_keys WHERE session_id = ?1)",
+        params![id],
+    )?;
+    tx.execute("DELETE FROM api_keys WHERE session_id = ?1", params![id])?;
+    tx.execute("DELETE FROM sim_bets WHERE session_id = ?1", params![id])?;
+    tx.execute("DELETE FROM wakeups WHERE session_id = ?1", params![id])?;
+    tx.execute("DELETE FROM webhooks WHERE session_id = ?1", params![id])?;
+    tx.execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+    tx.commit()?;
+
+    log::info!("deleted session {id}");
+
+    Ok(())
+}
+
+pub fn set_clock_mode(
+    conn: &Connection,
+    id: &str,
+    clock_mode: &ClockMode,
+) -> Result<Session, RowParsingError> {
+    if id == DEFAULT_SESSION_ID {
+        return Err(RowParsingError::InvalidRequest(
+            "the default session has no clock".to_string(),
+        ));
+    }
+    validate_clock_mode(clock_mode)?;
+    let session = get_session(conn, id)?;
+    set_session_clock_mode(
+        conn,
+        id,
+        &anchor_clock_mode(clock_mode.clone(), session.clock_time),
+    )?;
+
+    get_session(conn, id)
+}
+
+fn validate_clock_mode(clock_mode: &ClockMode) -> Result<(), RowParsingError> {
+    match clock_mode {
+        ClockMode::Realtime { rate, .. } if !(*rate > 0.0 && rate.is_finite()) => Err(
+            RowParsingError::InvalidRequest(format!("rate must be positive, got {rate}")),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// The time of the next historical event after the session clock: a bet, or a
+/// market being created, closing or resolving. None if there's nothing left.
+pub fn next_event_time(
+    conn: &Connection,
+    session: &Session,
+) -> Result<Option<u64>, RowParsingError> {
+    let query = "
+        SELECT MIN(t) FROM (
+          SELECT MIN(created_time) AS t FROM bets WHERE created_time > :clock_time
+          UNION ALL
+          SELECT MIN(created_time) FROM markets WHERE created_time > :clock_time
+          UNION ALL
+          SELECT MIN(close_time) FROM markets WHERE close_time > :clock_time
+          UNION ALL
+          SELECT MIN(resolution_time) FROM markets WHERE resolution_time > :clock_time
+        );";
+
+    let next_event_time = conn.query_row(
+        query,
+        named_params! { ":clock_time": session.clock_time },
+        |row| row.get::<_, Option<u64>>(0),
+    )?;
+
+    Ok(next_event_time)
+}
+
+/// The time of the next historical event after the session clock that the
+/// session's simulated users could care about: a market being created, a bet,
+/// close or resolution on a market they've bet on or have orders in (which is
+/// also when their limit orders can fill), one of their orders expiring, or
+/// one of their wakeups firing. None if there's nothing left.
+pub fn next_relevant_event_time(
+    conn: &Connection,
+    session: &Session,
+) -> Result<Option<u64>, RowParsingError> {
+    let query = "
+        WITH held AS (
+          SELECT DISTINCT contract_id FROM sim_bets WHERE session_id = :session_id
+        )
+        SELECT MIN(t) FROM (
+          SELECT MIN(created_time) AS t FROM markets WHERE created_time > :clock_time
+          UNION ALL
+          SELECT MIN(created_time) FROM bets
+            WHERE created_time > :clock_time AND contract_id IN held
+          UNION ALL
+          SELECT MIN(close_time) FROM markets
+            WHERE close_time > :clock_time AND id IN held
+          UNION ALL
+          SELECT MIN(resolution_time) FROM markets
+            WHERE resolution_time > :clock_time AND id IN held
+          UNION ALL
+          SELECT MIN(json_extract(limit_props, '$.expiresAt')) FROM sim_bets
+            WHERE session_id = :session_id
+              AND json_extract(limit_props, '$.expiresAt') > :clock_time
+              AND NOT json_extract(limit_props, '$.isFilled')
+              AND NOT json_extract(limit_props, '$.isCancelled')
+          UNION ALL
+          SELECT MIN(at) FROM wakeups
+            WHERE session_id = :session_id AND fired_time IS NULL AND at > :clock_time
+          UNION ALL
+          SELECT MIN(bets.created_time) FROM wakeups JOIN bets ON bets.contract_id = wakeups.market_id
+            WHERE wakeups.session_id = :session_id AND wakeups.fired_time IS NULL
+              AND bets.answer_id IS NULL AND bets.created_time > :clock_time
+              AND ABS(bets.prob_after - wakeups.reference_probability) * 100 > wakeups.points
+        );";
+
+    let next_event_time = conn.query_row(
+        query,
+        named_params! { ":clock_time": session.clock_time, ":session_id": session.id },
+        |row| row.get::<_, Option<u64>>(0),
+    )?;
+
+    Ok(next_event_time)
+}
+
+/// Sets a wakeup in session `id`, either at simulated time `at`, or for when
+/// market `market_id` moves more than `points` percentage points from where it is now
+pub fn add_wakeup(
+    conn: &Connection,
+    id: &str,
+    at: Option<u64>,
+    market_id: Option<&str>,
+    points: Option<f64>,
+) -> Result<Wakeup, RowParsingError> {
+    let session = get_session(conn, id)?;
+    let clock_time = session
+        .clock_time
+        .ok_or_else(|| RowParsingError::InvalidRequest(format!("session {id} has no clock")))?;
+
+    let mut wakeup = Wakeup {
+        id: new_id(),
+        created_time: clock_time,
+        at: None,
+        market_id: None,
+        points: None,
+        reference_probability: None,
+        fired_time: None,
+    };
+
+    match (at, market_id, points) {
+        (Some(at), None, None) => {
+            if at <= clock_time {
+                return Err(RowParsingError::InvalidRequest(format!(
+                    "can't wake up at {at}, the session clock is already at {clock_time}"
+                )));
+            }
+            wakeup.at = Some(at);
+        }
+        (None, Some(market_id), Some(points)) => {
+            if !(points > 0.0 && points < 100.0) {
+                return Err(RowParsingError::InvalidRequest(format!(
+                    "points must be between 0 and 100, got {points}"
+                )));
+            }
+            let probability = simulation::get_market_as_of(conn, market_id, Some(clock_time))?
+                .ok_or_else(|| RowParsingError::MarketNotFound(market_id.to_string()))?
+                .probability
+                .ok_or_else(|| {
+                    RowParsingError::InvalidRequest(format!(
+                        "market {market_id} has no probability to watch"
+                    ))
+                })?;
+
+            wakeup.market_id = Some(market_id.to_string());
+            wakeup.points = Some(points);
+            wakeup.reference_probability = Some(probability);
+        }
+        _ => {
+            return Err(RowParsingError::InvalidRequest(
+                "give either 'at', or 'marketId' and 'points'".to_string(),
+            ))
+        }
+    }
+
+    insert_wakeup(conn, id, &wakeup)?;
+
+    Ok(wakeup)
+}
+
+/// All of session `id`'s wakeups, including the ones that have fired
+pub fn get_wakeups(conn: &Connection, id: &str) -> Result<Vec<Wakeup>, RowParsingError> {
+    get_session(conn, id)?;
+    wakeup_table::get_wakeups(conn, id)
+}
+
+pub fn delete_wakeup(conn: &Connection, id: &str, wakeup_id: &str) -> Result<(), RowParsingError> {
+    if wakeup_table::delete_wakeup(conn, id, wakeup_id)? == 0 {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "session {id} has no wakeup {wakeup_id}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Registers a webhook on session `id`. Only http urls on this machine are
+/// allowed, since this is for local test harnesses.
+pub fn add_webhook(
+    conn: &Connection,
+    id: &str,
+    url: &str,
+    events: Vec<WebhookEvent>,
+    market_ids: Vec<String>,
+) -> Result<Webhook, RowParsingError> {
+    get_session(conn, id)?;
+
+    let uri = url
+        .parse::<hyper::Uri>()
+        .map_err(|e| RowParsingError::InvalidRequest(format!("bad url {url}: {e}")))?;
+    let is_local = matches!(uri.host(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if uri.scheme_str() != Some("http") || !is_local {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "webhook urls must be http://localhost, http://127.0.0.1 or http://[::1], got {url}"
+        )));
+    }
+
+    if events.is_empty() {
+        return Err(RowParsingError::InvalidRequest(
+            "give at least one event type".to_string(),
+        ));
+    }
+
+    let webhook = Webhook {
+        id: new_id(),
+        url: url.to_string(),
+        events,
+        market_ids,
+        created_time: now_millis(),
+    };
+    insert_webhook(conn, id, &webhook)?;
+
+    log::info!("added webhook {} to session {id}", webhook.id);
+
+    Ok(webhook)
+}
+
+/// Session `id`'s webhooks
+pub fn get_webhooks(conn: &Connection, id: &str) -> Result<Vec<Webhook>, RowParsingError> {
+    get_session(conn, id)?;
+    let webhooks = webhook_table::get_webhooks(conn, Some(id))?;
+    Ok(webhooks.into_iter().map(|(_, webhook)| webhook).collect())
+}
+
+/// Every session's webhooks, as (session id, webhook) pairs
+pub fn get_all_webhooks(conn: &Connection) -> Result<Vec<(String, Webhook)>, RowParsingError> {
+    webhook_table::get_webhooks(conn, None)
+}
+
+pub fn delete_webhook(
+    conn: &Connection,
+    id: &str,
+    webhook_id: &str,
+) -> Result<(), RowParsingError> {
+    if webhook_table::delete_webhook(conn, id, webhook_id)? == 0 {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "session {id} has no webhook {webhook_id}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Writes a snapshot of the session to the file at `path`, in `snapshot_dir`
+pub fn save_session_snapshot(
+    conn: &Connection,
+    id: &str,
+    snapshot_dir: &Path,
+    path: &str,
+) -> Result<(), RowParsingError> {
+    let file_path = snapshot::snapshot_file_path(snapshot_dir, path)?;
+
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Deferred)?;
+    let session = get_session(&tx, id)?;
+    let snapshot = snapshot::snapshot_session(&tx, &session)?;
+    tx.finish()?;
+
+    snapshot::write_snapshot_file(&file_path, path, &snapshot)?;
+
+    log::info!("saved snapshot of session {id} to {}", file_path.display());
+
+    Ok(())
+}
+
+/// Creates a new session from the snapshot in the file at `path`, in `snapshot_dir`
+pub fn restore_session_snapshot(
+    conn: &Connection,
+    snapshot_dir: &Path,
+    path: &str,
+) -> Result<Session, RowParsingError> {
+    let file_path = snapshot::snapshot_file_path(snapshot_dir, path)?;
+
+    let snapshot = snapshot::read_snapshot_file(&file_path, path)?;
+
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let session = snapshot::restore_session(&tx, &snapshot)?;
+    tx.commit()?;
+
+    log::info!(
+        "restored session {} from {}",
+        session.id,
+        file_path.display()
+    );
+
+    Ok(session)
+}
+
+/// Creates a new session that starts out as an exact copy of session `id`
+pub fn fork_session(conn: &Connection, id: &str) -> Result<Session, RowParsingError> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let session = get_session(&tx, id)?;
+    let snapshot = snapshot::snapshot_session(&tx, &session)?;
+    let forked_session = snapshot::restore_session(&tx, &snapshot)?;
+    tx.commit()?;
+
+    log::info!("forked session {id} into {}", forked_session.id);
 
-    Ok(user_json)
+    Ok(forked_session)
+}
+
+/// Moves the session clock forward to `to`
+pub fn advance_session_clock(
+    conn: &Connection,
+    id: &str,
+    to: u64,
+) -> Result<Session, RowParsingError> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let session = get_session(&tx, id)?;
+    simulation::advance_clock(&tx, &session, to)?;
+
+    // a realtime clock carries on from wherever it was moved to
+    if let ClockMode::Realtime { .. } = session.clock_mode {
+        set_session_clock_mode(&tx, id, &anchor_clock_mode(session.clock_mode, Some(to)))?;
+    }
+    tx.commit()?;
+
+    get_session(conn, id)
+}
+
+/// Moves every realtime session's clock up to where it should be by now
+pub fn sync_realtime_clocks(conn: &Connection) -> Result<(), RowParsingError> {
+    for id in get_session_ids_with_clock_mode(conn, "realtime")? {
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        let session = get_session(&tx, &id)?;
+
+        if let ClockMode::Realtime {
+            rate,
+            anchor_wall_time,
+            anchor_clock_time,
+        } = session.clock_mode
+        {
+            let elapsed = now_millis().saturating_sub(anchor_wall_time) as f64;
+            let to = anchor_clock_time + (elapsed * rate) as u64;
+            simulation::advance_clock(&tx, &session, to)?;
+        }
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Impls POST /v0/bet
+pub fn place_bet(
+    conn: &Connection,
+    session: &Session,
+    api_key: Option<&str>,
+    starting_balance: f64,
+    request: &BetRequest,
+) -> Result<Bet, RowParsingError> {
+    let user_id = get_request_user_id(conn, session, api_key, starting_balance)?;
+
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let bet = simulation::place_bet(&tx, session, &user_id, request)?;
+    tx.commit()?;
+
+    Ok(bet)
+}
+
+/// Impls POST /v0/bet/cancel/[id]
+pub fn cancel_bet(
+    conn: &Connection,
+    session: &Session,
+    api_key: Option<&str>,
+    starting_balance: f64,
+    bet_id: &str,
+) -> Result<Bet, RowParsingError> {
+    let user_id = get_request_user_id(conn, session, api_key, starting_balance)?;
+
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let bet = simulation::cancel_bet(&tx, session, &user_id, bet_id)?;
+    tx.commit()?;
+
+    Ok(bet)
+}
+
+/// Impls GET /v0/market/[marketId]/positions
+/// Only the session's simulated users have positions.
+pub fn get_positions(
+    conn: &Connection,
+    session: &Session,
+    market_id: &str,
+    user_id: Option<&str>,
+) -> Result<Vec<ContractMetric>, RowParsingError> {
+    simulation::get_positions(conn, session, market_id, user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{LeaderboardMetric, TimePeriod};
+    use crate::db::db_common::{get_db_connection, new_id};
+    use crate::db::test_fixture::{bets, Fixture, DAY, HOUR, START};
+    use std::collections::HashSet;
+
+    /// The default session, which sees all of the data, and one partway through it
+    fn sessions(conn: &Connection) -> Vec<Session> {
+        vec![
+            get_session(conn, DEFAULT_SESSION_ID).unwrap(),
+            create_session(conn, Some(START + 3 * HOUR), ClockMode::Manual).unwrap(),
+            create_session(conn, Some(START + 8 * HOUR), ClockMode::Manual).unwrap(),
+        ]
+    }
+
+    /// Checks that `pages` are `all`, split up, with nothing on two pages
+    fn assert_pages_cover(pages: Vec<Vec<String>>, all: Vec<String>, what: &str) {
+        assert!(pages.iter().all(|page| !page.is_empty()), "{what}");
+        let paged: Vec<String> = pages.into_iter().flatten().collect();
+        let unique: HashSet<&String> = paged.iter().collect();
+        assert_eq!(unique.len(), paged.len(), "{what} has duplicates");
+        assert_eq!(paged, all, "{what}");
+    }
+
+    #[test]
+    fn market_pages_cover_every_market_once() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+
+        let sorts = [
+            "created-time",
+            "updated-time",
+            "last-bet-time",
+            "volume",
+            "close-time",
+            "total-liquidity",
+        ];
+        for session in sessions(&conn) {
+            for sort in sorts {
+                // liquidity is only known in the default session
+                if sort == "total-liquidity" && session.clock_time.is_some() {
+                    let query = MarketQuery {
+                        sort: Some(sort.to_string()),
+                        ..Default::default()
+                    };
+                    let markets = get_markets(&conn, &session, &query);
+                    assert!(matches!(markets, Err(RowParsingError::InvalidRequest(_))));
+                    continue;
+                }
+
+                // no order is the same as desc
+                for order in [None, Some("asc"), Some("desc")] {
+                    let what = format!("markets by {sort} {order:?} at {:?}", session.clock_time);
+                    let query = MarketQuery {
+                        sort: Some(sort.to_string()),
+                        order: order.map(str::to_string),
+                        ..Default::default()
+                    };
+                    let ids = |markets: Vec<LiteMarket>| -> Vec<String> {
+                        markets.into_iter().map(|market| market.id).collect()
+                    };
+
+                    let all = ids(get_markets(&conn, &session, &query).unwrap());
+                    assert!(!all.is_empty(), "{what}");
+                    if order.is_none() {
+                        let desc = MarketQuery {
+                            order: Some("desc".to_string()),
+                            ..query.clone()
+                        };
+                        assert_eq!(all, ids(get_markets(&conn, &session, &desc).unwrap()));
+                    }
+
+                    let mut pages = Vec::new();
+                    let mut before = None;
+                    loop {
+                        let page_query = MarketQuery {
+                            limit: Some(3),
+                            before: before.clone(),
+                            ..query.clone()
+                        };
+                        let page = ids(get_markets(&conn, &session, &page_query).unwrap());
+                        if page.is_empty() {
+                            break;
+                        }
+                        before = page.last().cloned();
+                        pages.push(page);
+                    }
+
+                    assert_pages_cover(pages, all, &what);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bet_pages_cover_every_bet_once() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+
+        for session in sessions(&conn) {
+            // no order is the same as desc
+            for order in [None, Some("asc"), Some("desc")] {
+                let query = BetQuery {
+                    order: order.map(str::to_string),
+                    ..Default::default()
+                };
+                let ids = |bets: Vec<Bet>| -> Vec<String> {
+                    bets.into_iter().map(|bet| bet.id).collect()
+                };
+
+                let all = ids(get_bets(&conn, &session, &query).unwrap());
+                if session.clock_time.is_some_and(|t| t < START + 6 * HOUR) {
+                    assert!(all.is_empty());
+                    continue;
+                }
+                assert!(!all.is_empty());
+
+                // on from the start with `before`
+                let what = format!("bets {order:?} by before at {:?}", session.clock_time);
+                let mut pages: Vec<Vec<String>> = Vec::new();
+                loop {
+                    let page_query = BetQuery {
+                        limit: Some(5),
+                        before: pages.last().and_then(|page| page.last()).cloned(),
+                        ..query.clone()
+                    };
+                    let page = ids(get_bets(&conn, &session, &page_query).unwrap());
+                    if page.is_empty() {
+                        break;
+                    }
+                    pages.push(page);
+                }
+                assert_pages_cover(pages, all.clone(), &what);
+
+                // back from the last bet with `after`
+                let what = format!("bets {order:?} by after at {:?}", session.clock_time);
+                let mut pages = vec![vec![all.last().unwrap().clone()]];
+                loop {
+                    let page_query = BetQuery {
+                        limit: Some(5),
+                        after: pages.last().and_then(|page| page.first()).cloned(),
+                        ..query.clone()
+                    };
+                    let page = ids(get_bets(&conn, &session, &page_query).unwrap());
+                    if page.is_empty() {
+                        break;
+                    }
+                    pages.push(page);
+                }
+                pages.reverse();
+                assert_pages_cover(pages, all, &what);
+            }
+        }
+    }
+
+    #[test]
+    fn open_limit_orders_are_open_until_their_last_fill() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+
+        let query = BetQuery {
+            kinds: Some("open-limit".to_string()),
+            ..Default::default()
+        };
+        for session in sessions(&conn) {
+            let as_of = session.clock_time.unwrap_or(u64::MAX);
+            let mut expected: Vec<String> = bets()
+                .into_iter()
+                .filter(|bet| bet.created_time <= as_of)
+                .filter(|bet| {
+                    bet.limit_props.as_ref().is_some_and(|limit_props| {
+                        let last_fill = limit_props.fills.iter().map(|fill| fill.timestamp).max();
+                        !limit_props.is_filled || last_fill.is_some_and(|time| time > as_of)
+                    })
+                })
+                .map(|bet| bet.id)
+                .collect();
+            expected.sort();
+
+            let mut open: Vec<String> = get_bets(&conn, &session, &query)
+                .unwrap()
+                .into_iter()
+                .map(|bet| bet.id)
+                .collect();
+            open.sort();
+
+            assert_eq!(open, expected, "at {:?}", session.clock_time);
+        }
+
+        // bet00 fills at hours 7 and 8, so it's still open between them
+        let session = create_session(
+            &conn,
+            Some(START + 7 * HOUR + 30 * 60 * 1000),
+            ClockMode::Manual,
+        )
+        .unwrap();
+        let open = get_bets(&conn, &session, &query).unwrap();
+        assert!(open.iter().any(|bet| bet.id == "bet00"));
+    }
+
+    #[test]
+    fn limit_orders_only_show_fills_up_to_the_clock() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+
+        let bet00 = |session: &Session| -> Bet {
+            let query = BetQuery {
+                contract_id: Some("market00".to_string()),
+                ..Default::default()
+            };
+            get_bets(&conn, session, &query)
+                .unwrap()
+                .into_iter()
+                .find(|bet| bet.id == "bet00")
+                .unwrap()
+        };
+        let fill_times = |bet: &Bet| -> Vec<u64> {
+            let limit_props = bet.limit_props.as_ref().unwrap();
+            limit_props
+                .fills
+                .iter()
+                .map(|fill| fill.timestamp)
+                .collect()
+        };
+        let created_time = START + 6 * HOUR + 7 * 60 * 1000;
+
+        // bet00 fills in two halves, at hours 7 and 8
+        let before_fills =
+            create_session(&conn, Some(START + 7 * HOUR), ClockMode::Manual).unwrap();
+        let bet = bet00(&before_fills);
+        assert_eq!(fill_times(&bet), Vec::<u64>::new());
+        assert_eq!((bet.amount, bet.shares), (0.0, 0.0));
+        assert!(!bet.limit_props.as_ref().unwrap().is_filled);
+
+        let between_fills = create_session(
+            &conn,
+            Some(START + 7 * HOUR + 30 * 60 * 1000),
+            ClockMode::Manual,
+        )
+        .unwrap();
+        let bet = bet00(&between_fills);
+        assert_eq!(fill_times(&bet), vec![created_time + HOUR]);
+        assert_eq!((bet.amount, bet.shares), (25.0, 50.0));
+        assert!(!bet.limit_props.as_ref().unwrap().is_filled);
+
+        let after_fills = create_session(&conn, Some(START + 9 * HOUR), ClockMode::Manual).unwrap();
+        let bet = bet00(&after_fills);
+        assert_eq!(
+            fill_times(&bet),
+            vec![created_time + HOUR, created_time + 2 * HOUR]
+        );
+        assert_eq!((bet.amount, bet.shares), (50.0, 100.0));
+        assert!(bet.limit_props.as_ref().unwrap().is_filled);
+    }
+
+    #[test]
+    fn profits_match_positions() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+
+        // before market00's first bet, which moves it from 0.3 to 0.35
+        let session = create_session(&conn, Some(START + 2 * HOUR), ClockMode::Manual).unwrap();
+        let request = BetRequest {
+            contract_id: "market00".to_string(),
+            amount: 10.0,
+            outcome: "YES".to_string(),
+            limit_prob: None,
+            expires_at: None,
+        };
+        let bet = place_bet(&conn, &session, Some("bot"), 1000.0, &request).unwrap();
+        assert!((bet.shares - 10.0 / 0.3).abs() < 1e-9);
+
+        for (clock_time, expected_profit) in [
+            (START + 2 * HOUR, 0.0),
+            (START + 7 * HOUR, bet.shares * 0.35 - 10.0),
+        ] {
+            let session = advance_session_clock(&conn, &session.id, clock_time).unwrap();
+            let me = get_me(&conn, &session, Some("bot"), 1000.0).unwrap();
+            let positions = get_positions(&conn, &session, "market00", Some(&me.id)).unwrap();
+
+            let all_time = me.profit_cached[&TimePeriod::AllTime];
+            assert!((all_time - expected_profit).abs() < 1e-9, "at {clock_time}");
+            assert!(
+                (all_time - positions[0].profit).abs() < 1e-9,
+                "at {clock_time}"
+            );
+            // the bet was placed within the last day
+            assert_eq!(me.profit_cached[&TimePeriod::Daily], all_time);
+        }
+    }
+
+    #[test]
+    fn leaderboard_only_counts_bets_in_the_window() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+
+        // a day from hour 8 holds the last 24 of the 48 bets, 8 from each user
+        let session =
+            create_session(&conn, Some(START + DAY + 8 * HOUR), ClockMode::Manual).unwrap();
+        for metric in [LeaderboardMetric::Profit, LeaderboardMetric::Volume] {
+            let leaderboard = |min_bets| {
+                get_leaderboard(
+                    &conn,
+                    session.clock_time,
+                    metric,
+                    Some(1),
+                    Some(min_bets),
+                    None,
+                )
+                .unwrap()
+            };
+
+            let entries = leaderboard(8);
+            assert_eq!(entries.len(), 3, "{metric:?}");
+            assert!(
+                entries.iter().all(|entry| entry.bet_count == 8),
+                "{metric:?}"
+            );
+            assert!(leaderboard(9).is_empty(), "{metric:?}");
+        }
+
+        for limit in [-1, 1001] {
+            let leaderboard = get_leaderboard(
+                &conn,
+                session.clock_time,
+                LeaderboardMetric::Profit,
+                None,
+                None,
+                Some(limit),
+            );
+            assert!(matches!(
+                leaderboard,
+                Err(RowParsingError::InvalidRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn bets_filter_on_username_not_display_name() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+        let session = get_session(&conn, DEFAULT_SESSION_ID).unwrap();
+
+        let by_username = |username: &str| {
+            let query = BetQuery {
+                username: Some(username.to_string()),
+                ..Default::default()
+            };
+            get_bets(&conn, &session, &query).unwrap()
+        };
+
+        let bets = by_username("user1");
+        assert_eq!(bets.len(), 16);
+        assert!(bets.iter().all(|bet| bet.user_id == "user1"));
+        assert!(by_username("User 1").is_empty());
+    }
+
+    #[test]
+    fn user_pages_only_start_from_a_visible_user() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+
+        for session in sessions(&conn) {
+            let users = get_users(&conn, &session, None, None).unwrap();
+            if let Some(first) = users.first() {
+                let rest = get_users(&conn, &session, None, Some(&first.user.id)).unwrap();
+                assert_eq!(rest.len(), users.len() - 1);
+            }
+
+            let unknown = get_users(&conn, &session, None, Some("nobody"));
+            assert!(matches!(unknown, Err(RowParsingError::InvalidRequest(_))));
+        }
+    }
+
+    #[test]
+    fn snapshots_and_forks_carry_on_where_the_session_was() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+        let snapshot_dir = std::env::temp_dir().join(format!("mmmbacktest-test-{}", new_id()));
+
+        let session = create_session(&conn, Some(START + 2 * HOUR), ClockMode::Manual).unwrap();
+        let request = BetRequest {
+            contract_id: "market00".to_string(),
+            amount: 10.0,
+            outcome: "YES".to_string(),
+            limit_prob: None,
+            expires_at: None,
+        };
+        place_bet(&conn, &session, Some("bot"), 1000.0, &request).unwrap();
+        let me = get_me(&conn, &session, Some("bot"), 1000.0).unwrap();
+
+        save_session_snapshot(&conn, &session.id, &snapshot_dir, "runs/a.json").unwrap();
+        // saved through a temporary file, which is gone once it's in place
+        let files: Vec<_> = std::fs::read_dir(snapshot_dir.join("runs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["a.json"]);
+
+        let restored = restore_session_snapshot(&conn, &snapshot_dir, "runs/a.json").unwrap();
+        let forked = fork_session(&conn, &session.id).unwrap();
+        for copy in [restored, forked] {
+            assert_ne!(copy.id, session.id);
+            assert_eq!(copy.clock_time, session.clock_time);
+
+            // the bot's api key still works, as the same user in the copy
+            let copied_me = get_me(&conn, &copy, Some("bot"), 1000.0).unwrap();
+            assert_eq!(copied_me.balance, me.balance);
+            let query = BetQuery {
+                user_id: Some(copied_me.id.clone()),
+                ..Default::default()
+            };
+            assert_eq!(get_bets(&conn, &copy, &query).unwrap().len(), 1);
+        }
+
+        // a path that doesn't lead to a snapshot is the client's to fix
+        for path in [
+            "runs/missing.json",
+            "runs",
+            "runs/a.json/b.json",
+            "../a.json",
+        ] {
+            let restored = restore_session_snapshot(&conn, &snapshot_dir, path);
+            assert!(
+                matches!(restored, Err(RowParsingError::InvalidRequest(_))),
+                "{path}"
+            );
+        }
+
+        let saved = save_session_snapshot(&conn, &session.id, &snapshot_dir, "runs/a.json/b.json");
+        assert!(matches!(saved, Err(RowParsingError::InvalidRequest(_))));
+
+        std::fs::remove_dir_all(&snapshot_dir).unwrap();
+    }
 }
@@ -0,0 +1,156 @@
+//! Profit reconstruction. A user's profit at some time is what their positions
+//! were worth then, less what they put in: resolved markets pay out by their
+//! resolution, and open ones are marked to the market's probability at the
+//! time. Only BINARY markets are counted, since those are the only ones whose
+//! positions can be valued from the bets.
+
+use rusqlite::{named_params, Connection};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::data_types::TimePeriod;
+use crate::db::errors::RowParsingError;
+use crate::db::market_table::MARKETS_AS_OF;
+use crate::db::sim_bet_table::SIM_BET_COLUMNS;
+
+pub const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Profit per user as of `:as_of`, from the historical bets if `:session_id`
+/// is NULL, and from the session's simulated bets otherwise. A NULL `:as_of`
+/// means the end of the data, and a NULL `:user_ids` (a JSON array) means
+/// every user.
+fn profit_query() -> String {
+    let bets_source = format!(
+        "SELECT * FROM bets WHERE :session_id IS NULL
+        UNION ALL
+        SELECT {SIM_BET_COLUMNS} FROM sim_bets WHERE session_id = :session_id"
+    );
+
+    format!(
+        "WITH positions AS (
+            SELECT user_id, contract_id,
+              SUM(CASE WHEN outcome = 'YES' THEN shares ELSE 0 END) AS yes_shares,
+              SUM(CASE WHEN outcome = 'NO' THEN shares ELSE 0 END) AS no_shares,
+              SUM(amount) AS invested
+            FROM ({bets_source})
+            WHERE answer_id IS NULL
+              AND (:as_of IS NULL OR created_time <= :as_of)
+              AND (:user_ids IS NULL OR user_id IN (SELECT value FROM json_each(:user_ids)))
+            GROUP BY user_id, contract_id
+        ),
+        -- valued at the market's probability as of :as_of, like positions are
+        valued AS (
+            SELECT p.user_id, p.invested, p.yes_shares, p.no_shares,
+              m.is_resolved AS resolved, m.resolution, m.resolution_probability,
+              COALESCE(m.probability, 0.5) AS prob
+            FROM positions p JOIN ({MARKETS_AS_OF}) m ON m.id = p.contract_id
+            WHERE m.outcome_type = '\"BINARY\"'
+        )
+        SELECT user_id, SUM(
+          CASE
+            WHEN resolved AND resolution = 'YES' THEN yes_shares
+            WHEN resolved AND resolution = 'NO' THEN no_shares
+            WHEN resolved AND resolution = 'MKT' THEN
+              yes_shares * COALESCE(resolution_probability, prob)
+                + no_shares * (1 - COALESCE(resolution_probability, prob))
+            -- CANCEL, or anything else, gives the mana back
+            WHEN resolved THEN invested
+            ELSE yes_shares * prob + no_shares * (1 - prob)
+          END - invested
+        ) AS profit
+        FROM valued
+        GROUP BY user_id"
+    )
+}
+
+/// Profit per user as of `as_of`, from the historical bets, or from the
+/// session's simulated bets if `session_id` is given.
+/// Only the users in `user_ids` if it's given.
+pub fn get_profits_as_of(
+    conn: &Connection,
+    session_id: Option<&str>,
+    user_ids: Option<&[String]>,
+    as_of: Option<u64>,
+) -> Result<HashMap<String, f64>, RowParsingError> {
+    let user_ids = user_ids.map(|user_ids| json!(user_ids).to_string());
+
+    let mut stmt = conn.prepare(&profit_query())?;
+    let params =
+        named_params! { ":as_of": as_of, ":user_ids": user_ids, ":session_id": session_id };
+
+    let rows = stmt.query_map(params, |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?))
+    })?;
+
+    let mut profits = HashMap::new();
+    for row in rows {
+        let (user_id, profit) = row?;
+        profits.insert(user_id, profit);
+    }
+
+    Ok(profits)
+}
+
+/// Where periods ending at `as_of` end: `as_of` itself, or the last
+/// historical bet without it
+pub fn period_end(conn: &Connection, as_of: Option<u64>) -> Result<u64, RowParsingError> {
+    match as_of {
+        Some(as_of) => Ok(as_of),
+        None => Ok(conn
+            .query_row("SELECT MAX(created_time) FROM bets", [], |row| {
+                row.get::<_, Option<u64>>(0)
+            })?
+            .unwrap_or(0)),
+    }
+}
+
+/// Each user's profit over each period ending at `as_of`, with one query per
+/// period for all of them. Without `as_of`, the periods end at the last
+/// historical bet.
+pub fn get_profits_cached(
+    conn: &Connection,
+    session_id: Option<&str>,
+    user_ids: &[String],
+    as_of: Option<u64>,
+) -> Result<HashMap<String, HashMap<TimePeriod, f64>>, RowParsingError> {
+    let end = period_end(conn, as_of)?;
+
+    let all_time = get_profits_as_of(conn, session_id, Some(user_ids), as_of)?;
+    let mut profits: HashMap<String, HashMap<TimePeriod, f64>> = user_ids
+        .iter()
+        .map(|user_id| {
+            let profit = all_time.get(user_id).copied().unwrap_or(0.0);
+            (
+                user_id.clone(),
+                HashMap::from([(TimePeriod::AllTime, profit)]),
+            )
+        })
+        .collect();
+
+    for (period, days) in [
+        (TimePeriod::Daily, 1),
+        (TimePeriod::Weekly, 7),
+        (TimePeriod::Monthly, 30),
+    ] {
+        let start = Some(end.saturating_sub(days * DAY_MS));
+        let before = get_profits_as_of(conn, session_id, Some(user_ids), start)?;
+        for (user_id, user_profits) in &mut profits {
+            let since =
+                user_profits[&TimePeriod::AllTime] - before.get(user_id).copied().unwrap_or(0.0);
+            user_profits.insert(period.clone(), since);
+        }
+    }
+
+    Ok(profits)
+}
+
+/// The user's profit over each period ending at `as_of`
+pub fn get_profit_cached(
+    conn: &Connection,
+    session_id: Option<&str>,
+    user_id: &str,
+    as_of: Option<u64>,
+) -> Result<HashMap<TimePeriod, f64>, RowParsingError> {
+    let mut profits = get_profits_cached(conn, session_id, &[user_id.to_string()], as_of)?;
+    Ok(profits.remove(user_id).unwrap_or_default())
+}
@@ -0,0 +1,124 @@
+use log::debug;
+use rusqlite::{named_params, params, Connection, OptionalExtension, Result, Row};
+
+use crate::data_types::{ClockMode, Session};
+use crate::db::db_common;
+use crate::db::errors::RowParsingError;
+
+/// The session used by requests that don't pick one. It has no clock, so
+/// it sees all of the backtest data, and it can't be advanced or deleted.
+pub const DEFAULT_SESSION_ID: &str = "default";
+
+pub fn create_session_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE sessions (
+            id TEXT PRIMARY KEY,
+            created_time BIGINT NOT NULL,
+            clock_time BIGINT,
+            clock_mode TEXT NOT NULL DEFAULT '{\"mode\":\"manual\"}'
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn insert_session(conn: &Connection, session: &Session) -> Result<usize> {
+    conn.execute(
+        "INSERT INTO sessions (id, created_time, clock_time, clock_mode) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            session.id,
+            session.created_time,
+            session.clock_time,
+            serde_json::to_string(&session.clock_mode).unwrap(),
+        ],
+    )
+}
+
+pub fn rusqlite_row_to_session(row: &Row) -> Result<Session, RowParsingError> {
+    let clock_mode_str: String = row.get(3)?;
+
+    Ok(Session {
+        id: row.get(0)?,
+        created_time: row.get(1)?,
+        clock_time: row.get(2)?,
+        clock_mode: serde_json::from_str::<ClockMode>(&clock_mode_str)?,
+    })
+}
+
+pub fn get_session(conn: &Connection, id: &str) -> Result<Option<Session>, RowParsingError> {
+    let maybe_session = conn
+        .query_row(
+            "SELECT * FROM sessions WHERE id = :id",
+            named_params! { ":id": id },
+            |row| Ok(rusqlite_row_to_session(row)),
+        )
+        .optional()?;
+
+    maybe_session.transpose()
+}
+
+pub fn set_session_clock(conn: &Connection, id: &str, clock_time: u64) -> Result<usize> {
+    conn.execute(
+        "UPDATE sessions SET clock_time = ?2 WHERE id = ?1",
+        params![id, clock_time],
+    )
+}
+
+pub fn set_session_clock_mode(
+    conn: &Connection,
+    id: &str,
+    clock_mode: &ClockMode,
+) -> Result<usize> {
+    conn.execute(
+        "UPDATE sessions SET clock_mode = ?2 WHERE id = ?1",
+        params![id, serde_json::to_string(clock_mode).unwrap()],
+    )
+}
+
+/// Realtime clocks run from the moment they're set, so every time the mode is
+/// set or the clock jumps, it's re-anchored to the wall clock. Other modes are
+/// returned as they are.
+pub fn anchor_clock_mode(clock_mode: ClockMode, clock_time: Option<u64>) -> ClockMode {
+    match clock_mode {
+        ClockMode::Realtime { rate, .. } => ClockMode::Realtime {
+            rate,
+            anchor_wall_time: db_common::now_millis(),
+            anchor_clock_time: clock_time.unwrap_or(0),
+        },
+        clock_mode => clock_mode,
+    }
+}
+
+/// Ids of the sessions whose clock mode is `mode`
+pub fn get_session_ids_with_clock_mode(conn: &Connection, mode: &str) -> Result<Vec<String>> {
+    let mut stmt =
+        conn.prepare("SELECT id FROM sessions WHERE json_extract(clock_mode, '$.mode') = ?1")?;
+    let rows = stmt.query_map(params![mode], |row| row.get(0))?;
+    rows.collect()
+}
+
+pub fn init_session_table(conn: &mut Connection) -> Result<usize> {
+    if !db_common::table_exists(conn, "sessions")? {
+        debug!("creating 'sessions' table");
+        create_session_table(conn)?;
+    } else {
+        debug!("found 'sessions' table");
+        db_common::add_column_if_missing(
+            conn,
+            "sessions",
+            "clock_mode",
+            "TEXT NOT NULL DEFAULT '{\"mode\":\"manual\"}'",
+        )?;
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO sessions (id, created_time, clock_time) VALUES (?1, 0, NULL)",
+        params![DEFAULT_SESSION_ID],
+    )?;
+
+    let num_rows =
+        db_common::count_rows(conn, "sessions").expect("failed to count rows in sessions table");
+    debug!("{num_rows} sessions found");
+
+    Ok(num_rows)
+}
@@ -0,0 +1,162 @@
+use log::debug;
+use rusqlite::{named_params, params, Connection, Result};
+
+use crate::data_types::Bet;
+use crate::db::bet_table::rusqlite_row_to_bet;
+use crate::db::db_common;
+use crate::db::errors::RowParsingError;
+
+/// Same columns as the bets table, in the same order, so that
+/// rusqlite_row_to_bet works on both
+pub const SIM_BET_COLUMNS: &str = "
+    id, user_id, user_avatar_url, user_name, user_username, contract_id, answer_id,
+    created_time, amount, loan_amount, outcome, shares,
+    prob_before, prob_after, fees, is_api, is_ante, is_redemption, is_challenge,
+    visibility, challenge_slug, reply_to_comment_id, limit_props";
+
+/// Every bet that a session can see: the historical bets up to `:as_of`, plus the
+/// session's own simulated bets. A NULL `:as_of` means all of the historical bets.
+pub fn bets_as_of_query() -> String {
+    format!(
+        "SELECT * FROM bets WHERE :as_of IS NULL OR created_time <= :as_of
+        UNION ALL
+        SELECT {SIM_BET_COLUMNS} FROM sim_bets WHERE session_id = :session_id"
+    )
+}
+
+pub fn create_sim_bet_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE sim_bets (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            user_avatar_url TEXT,
+            user_name TEXT,
+            user_username TEXT,
+            contract_id TEXT NOT NULL,
+            answer_id TEXT,
+            created_time BIGINT NOT NULL,
+            amount FLOAT NOT NULL,
+            loan_amount FLOAT,
+            outcome TEXT NOT NULL,
+            shares FLOAT NOT NULL,
+            prob_before FLOAT NOT NULL,
+            prob_after FLOAT NOT NULL,
+            fees TEXT,
+            is_api BOOL,
+            is_ante BOOL NOT NULL,
+            is_redemption BOOL NOT NULL,
+            is_challenge BOOL NOT NULL,
+            visibility TEXT,
+            challenge_slug TEXT,
+            reply_to_comment_id TEXT,
+            limit_props TEXT,
+            session_id TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn insert_sim_bet(conn: &Connection, session_id: &str, bet: &Bet) -> Result<usize> {
+    let stmt_str = format!(
+        "INSERT INTO sim_bets ({SIM_BET_COLUMNS}, session_id) VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7,
+            ?8, ?9, ?10, ?11, ?12, ?13,
+            ?14, ?15, ?16, ?17, ?18, ?19, ?20,
+            ?21, ?22, ?23, ?24
+        )"
+    );
+
+    let mut stmt = conn.prepare(&stmt_str)?;
+    stmt.execute(params![
+        bet.id,
+        bet.user_id,
+        bet.user_avatar_url,
+        bet.user_name,
+        bet.user_username,
+        bet.contract_id,
+        bet.answer_id,
+        bet.created_time,
+        bet.amount,
+        bet.loan_amount,
+        bet.outcome,
+        bet.shares,
+        bet.prob_before,
+        bet.prob_after,
+        serde_json::to_string(&bet.fees).unwrap(),
+        bet.is_api,
+        bet.is_ante,
+        bet.is_redemption,
+        bet.is_challenge,
+        serde_json::to_string(&bet.visibility).unwrap(),
+        bet.challenge_slug,
+        bet.reply_to_comment_id,
+        serde_json::to_string(&bet.limit_props).unwrap(),
+        session_id,
+    ])
+}
+
+/// The session's simulated bets, oldest first, optionally only those on one market
+pub fn get_sim_bets(
+    conn: &Connection,
+    session_id: &str,
+    contract_id: Option<&str>,
+) -> Result<Vec<Bet>, RowParsingError> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM sim_bets
+        WHERE
+          session_id = :session_id AND
+          (:contract_id IS NULL OR contract_id = :contract_id)
+        ORDER BY created_time ASC",
+    )?;
+
+    let bet_iter = stmt.query_map(
+        named_params! { ":session_id": session_id, ":contract_id": contract_id },
+        |row| Ok(rusqlite_row_to_bet(row)),
+    )?;
+
+    let mut bets = Vec::new();
+    for maybe_bet in bet_iter {
+        bets.push(maybe_bet??);
+    }
+
+    Ok(bets)
+}
+
+/// Writes back the parts of a simulated bet that change after it's placed,
+/// i.e. when a limit order gets filled or cancelled
+pub fn update_sim_bet(conn: &Connection, bet: &Bet) -> Result<usize> {
+    conn.execute(
+        "UPDATE sim_bets
+        SET amount = ?2, shares = ?3, prob_before = ?4, prob_after = ?5, limit_props = ?6
+        WHERE id = ?1",
+        params![
+            bet.id,
+            bet.amount,
+            bet.shares,
+            bet.prob_before,
+            bet.prob_after,
+            serde_json::to_string(&bet.limit_props).unwrap(),
+        ],
+    )
+}
+
+pub fn init_sim_bet_table(conn: &mut Connection) -> Result<usize> {
+    if !db_common::table_exists(conn, "sim_bets")? {
+        debug!("creating 'sim_bets' table");
+        create_sim_bet_table(conn)?;
+    } else {
+        debug!("found 'sim_bets' table");
+    }
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS sim_bets_index ON sim_bets (session_id, contract_id);",
+        [],
+    )?;
+
+    let num_rows =
+        db_common::count_rows(conn, "sim_bets").expect("failed to count rows in sim_bets table");
+    debug!("{num_rows} simulated bets found");
+
+    Ok(num_rows)
+}
@@ -0,0 +1,497 @@
+//! The simulated side of a session: placing bets, filling limit orders as the
+//! clock moves, and paying out positions when markets resolve.
+//!
+//! Simulated bets are price takers: they fill at the historical probability at
+//! the session clock, and they don't move the market. Only BINARY markets are
+//! supported.
+
+use rusqlite::{named_params, params, Connection, OptionalExtension};
+use std::collections::HashMap;
+
+use crate::data_types::{
+    Bet, BetRequest, ContractMetric, Fees, Fill, LimitProps, LiteMarket, MarketOutcome,
+    MarketOutcomeType, Session, Visibility,
+};
+use crate::db::db_common::new_id;
+use crate::db::errors::RowParsingError;
+use crate::db::market_table::{rusqlite_row_to_litemarket, MARKETS_AS_OF};
+use crate::db::session_table::set_session_clock;
+use crate::db::sim_bet_table::{get_sim_bets, insert_sim_bet, update_sim_bet};
+use crate::db::user_table::{add_to_balance, get_user, set_last_bet_time};
+use crate::db::wakeup_table::fire_wakeups;
+
+/// The price of one share of `outcome` when the market is at `prob`
+fn share_price(outcome: &str, prob: f64) -> f64 {
+    if outcome == "YES" {
+        prob
+    } else {
+        1.0 - prob
+    }
+}
+
+/// Whether a limit order for `outcome` at `limit_prob` can fill with the market at `prob`
+fn limit_crossed(outcome: &str, limit_prob: f64, prob: f64) -> bool {
+    if outcome == "YES" {
+        prob <= limit_prob
+    } else {
+        prob >= limit_prob
+    }
+}
+
+/// What a position of `yes_shares` and `no_shares` pays out. For unresolved
+/// markets, this is the value at `prob`.
+fn position_payout(
+    market: &LiteMarket,
+    yes_shares: f64,
+    no_shares: f64,
+    invested: f64,
+    prob: f64,
+) -> f64 {
+    if !market.is_resolved {
+        return yes_shares * prob + no_shares * (1.0 - prob);
+    }
+
+    match market.resolution.as_deref() {
+        Some("YES") => yes_shares,
+        Some("NO") => no_shares,
+        Some("MKT") => {
+            let p = market.resolution_probability.unwrap_or(prob);
+            yes_shares * p + no_shares * (1.0 - p)
+        }
+        // CANCEL, or anything we don't understand, gives the mana back
+        _ => invested,
+    }
+}
+
+/// The market with id `market_id`, as seen at `as_of`
+pub fn get_market_as_of(
+    conn: &Connection,
+    market_id: &str,
+    as_of: Option<u64>,
+) -> Result<Option<LiteMarket>, RowParsingError> {
+    let query = format!("SELECT * FROM ({MARKETS_AS_OF}) WHERE id = :id");
+
+    let maybe_market = conn
+        .query_row(
+            &query,
+            named_params! { ":id": market_id, ":as_of": as_of },
+            |row| Ok(rusqlite_row_to_litemarket(row)),
+        )
+        .optional()?;
+
+    maybe_market.transpose()
+}
+
+fn is_open_limit_order(bet: &Bet) -> bool {
+    match &bet.limit_props {
+        Some(lp) => !lp.is_filled && !lp.is_cancelled,
+        None => false,
+    }
+}
+
+/// Impls POST /v0/bet
+pub fn place_bet(
+    conn: &Connection,
+    session: &Session,
+    user_id: &str,
+    request: &BetRequest,
+) -> Result<Bet, RowParsingError> {
+    let contract_id = request.contract_id.as_str();
+    let outcome = request.outcome.as_str();
+    let (amount, limit_prob, expires_at) = (request.amount, request.limit_prob, request.expires_at);
+
+    let clock_time = session.clock_time.ok_or_else(|| {
+        RowParsingError::InvalidRequest(format!(
+            "session {} has no clock; create a session to place bets",
+            session.id
+        ))
+    })?;
+
+    if amount <= 0.0 || !amount.is_finite() {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "bet amount must be positive, got {amount}"
+        )));
+    }
+    if outcome != "YES" && outcome != "NO" {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "outcome must be YES or NO, got {outcome}"
+        )));
+    }
+    if let Some(limit_prob) = limit_prob {
+        if !(0.01..=0.99).contains(&limit_prob) {
+            return Err(RowParsingError::InvalidRequest(format!(
+                "limitProb must be between 0.01 and 0.99, got {limit_prob}"
+            )));
+        }
+    }
+
+    let market = get_market_as_of(conn, contract_id, Some(clock_time))?.ok_or_else(|| {
+        RowParsingError::MarketNotFound(format!("no market with id {contract_id}"))
+    })?;
+
+    if market.outcome_type != MarketOutcomeType::Binary {
+        return Err(RowParsingError::InvalidRequest(
+            "only BINARY markets can be bet on in the backtest".to_string(),
+        ));
+    }
+    if market.is_resolved {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "market {contract_id} is resolved"
+        )));
+    }
+    if market
+        .close_time
+        .is_some_and(|close_time| close_time <= clock_time as i64)
+    {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "market {contract_id} is closed"
+        )));
+    }
+
+    let user = get_user(conn, user_id)?
+        .ok_or_else(|| RowParsingError::UserNotFound(format!("no user with id {user_id}")))?;
+    if user.balance < amount {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "insufficient balance: {:.2} < {amount:.2}",
+            user.balance
+        )));
+    }
+
+    let prob = market.probability.ok_or_else(|| {
+        RowParsingError::Generic(format!("market {contract_id} has no probability"))
+    })?;
+
+    let fills_now = limit_prob.is_none_or(|limit_prob| limit_crossed(outcome, limit_prob, prob));
+    let (filled_amount, shares) = if fills_now {
+        (amount, amount / share_price(outcome, prob))
+    } else {
+        (0.0, 0.0)
+    };
+
+    let limit_props = limit_prob.map(|limit_prob| LimitProps {
+        order_amount: amount,
+        limit_prob,
+        is_filled: fills_now,
+        is_cancelled: false,
+        fills: if fills_now {
+            vec![Fill {
+                timestamp: clock_time,
+                matched_bet_id: None,
+                amount,
+                shares,
+            }]
+        } else {
+            vec![]
+        },
+        expires_at,
+    });
+
+    #[allow(deprecated)]
+    let bet = Bet {
+        id: new_id(),
+        user_id: user.id.clone(),
+        user_avatar_url: Some(user.avatar_url.clone()),
+        user_name: Some(user.name.clone()),
+        user_username: Some(user.username.clone()),
+        contract_id: contract_id.to_string(),
+        answer_id: None,
+        created_time: clock_time,
+        amount: filled_amount,
+        loan_amount: Some(0.0),
+        outcome: outcome.to_string(),
+        shares,
+        shares_by_outcome: None,
+        prob_before: prob,
+        prob_after: prob,
+        fees: Some(Fees {
+            creator_fee: 0.0,
+            platform_fee: 0.0,
+            liquidity_fee: 0.0,
+        }),
+        is_api: Some(true),
+        is_ante: false,
+        is_redemption: false,
+        is_challenge: false,
+        visibility: Visibility::Public,
+        challenge_slug: None,
+        reply_to_comment_id: None,
+        limit_props,
+    };
+
+    insert_sim_bet(conn, &session.id, &bet)?;
+    add_to_balance(conn, user_id, -filled_amount)?;
+    set_last_bet_time(conn, user_id, clock_time)?;
+
+    log::info!("{} placed bet {}: {bet}", user.username, bet.id);
+
+    Ok(bet)
+}
+
+/// Impls POST /v0/bet/cancel/[id]
+pub fn cancel_bet(
+    conn: &Connection,
+    session: &Session,
+    user_id: &str,
+    bet_id: &str,
+) -> Result<Bet, RowParsingError> {
+    let mut bet = get_sim_bets(conn, &session.id, None)?
+        .into_iter()
+        .find(|bet| bet.id == bet_id && bet.user_id == user_id)
+        .ok_or_else(|| RowParsingError::InvalidRequest(format!("no bet with id {bet_id}")))?;
+
+    if !is_open_limit_order(&bet) {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "bet {bet_id} is not an open limit order"
+        )));
+    }
+
+    if let Some(limit_props) = bet.limit_props.as_mut() {
+        limit_props.is_cancelled = true;
+    }
+    update_sim_bet(conn, &bet)?;
+
+    Ok(bet)
+}
+
+/// Fills (or expires) the session's open limit orders against the historical
+/// bets in (from, to]. A limit order fills in full, at its limit price, the first
+/// time a historical bet moves the market across the limit.
+fn fill_limit_orders(
+    conn: &Connection,
+    session_id: &str,
+    from: u64,
+    to: u64,
+) -> Result<(), RowParsingError> {
+    let open_orders: Vec<Bet> = get_sim_bets(conn, session_id, None)?
+        .into_iter()
+        .filter(is_open_limit_order)
+        .collect();
+
+    for mut bet in open_orders {
+        let limit_props = bet
+            .limit_props
+            .clone()
+            .expect("open orders are limit orders");
+
+        let crossing_query = if bet.outcome == "YES" {
+            "SELECT id, created_time FROM bets
+            WHERE contract_id = ?1 AND answer_id IS NULL
+              AND created_time > ?2 AND created_time <= ?3 AND prob_after <= ?4
+            ORDER BY created_time ASC LIMIT 1"
+        } else {
+            "SELECT id, created_time FROM bets
+            WHERE contract_id = ?1 AND answer_id IS NULL
+              AND created_time > ?2 AND created_time <= ?3 AND prob_after >= ?4
+            ORDER BY created_time ASC LIMIT 1"
+        };
+
+        let crossing: Option<(String, u64)> = conn
+            .query_row(
+                crossing_query,
+                params![bet.contract_id, from, to, limit_props.limit_prob],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let expires_at = limit_props.expires_at.unwrap_or(u64::MAX);
+        let balance = get_user(conn, &bet.user_id)?.map_or(0.0, |user| user.balance);
+
+        let new_limit_props = match crossing {
+            Some((matched_bet_id, timestamp))
+                if timestamp <= expires_at && balance >= limit_props.order_amount =>
+            {
+                let amount = limit_props.order_amount;
+                let shares = amount / share_price(&bet.outcome, limit_props.limit_prob);
+
+                bet.amount = amount;
+                bet.shares = shares;
+                bet.prob_before = limit_props.limit_prob;
+                bet.prob_after = limit_props.limit_prob;
+                add_to_balance(conn, &bet.user_id, -amount)?;
+
+                let mut fills = limit_props.fills.clone();
+                fills.push(Fill {
+                    timestamp,
+                    matched_bet_id: Some(matched_bet_id),
+                    amount,
+                    shares,
+                });
+                LimitProps {
+                    is_filled: true,
+                    fills,
+                    ..limit_props
+                }
+            }
+            // crossed, but after expiry or without the mana to pay for it
+            Some(_) => LimitProps {
+                is_cancelled: true,
+                ..limit_props
+            },
+            None if expires_at <= to => LimitProps {
+                is_cancelled: true,
+                ..limit_props
+            },
+            None => continue,
+        };
+
+        bet.limit_props = Some(new_limit_props);
+        update_sim_bet(conn, &bet)?;
+    }
+
+    Ok(())
+}
+
+/// Pays out the session's positions in markets that resolved in (from, to],
+/// and cancels any limit orders still open on them
+fn settle_resolutions(
+    conn: &Connection,
+    session_id: &str,
+    from: u64,
+    to: u64,
+) -> Result<(), RowParsingError> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT m.id FROM markets m
+        JOIN sim_bets s ON s.contract_id = m.id
+        WHERE s.session_id = ?1 AND m.is_resolved AND m.resolution_time > ?2 AND m.resolution_time <= ?3",
+    )?;
+    let market_ids = stmt
+        .query_map(params![session_id, from, to], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    for market_id in market_ids {
+        let market = match get_market_as_of(conn, &market_id, Some(to))? {
+            Some(market) => market,
+            None => continue,
+        };
+
+        // user id -> (yes shares, no shares, invested)
+        let mut positions: HashMap<String, (f64, f64, f64)> = HashMap::new();
+        for mut bet in get_sim_bets(conn, session_id, Some(&market_id))? {
+            if is_open_limit_order(&bet) {
+                if let Some(limit_props) = bet.limit_props.as_mut() {
+                    limit_props.is_cancelled = true;
+                }
+                update_sim_bet(conn, &bet)?;
+                continue;
+            }
+
+            let position = positions.entry(bet.user_id.clone()).or_default();
+            if bet.outcome == "YES" {
+                position.0 += bet.shares;
+            } else {
+                position.1 += bet.shares;
+            }
+            position.2 += bet.amount;
+        }
+
+        let prob = market.probability.unwrap_or(0.5);
+        for (user_id, (yes_shares, no_shares, invested)) in positions {
+            let payout = position_payout(&market, yes_shares, no_shares, invested, prob);
+            add_to_balance(conn, &user_id, payout)?;
+            log::info!("paid {payout:.2} to {user_id} for market {market_id}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the session clock forward to `to`, filling limit orders and paying out
+/// resolved markets along the way
+pub fn advance_clock(conn: &Connection, session: &Session, to: u64) -> Result<(), RowParsingError> {
+    let from = session.clock_time.ok_or_else(|| {
+        RowParsingError::InvalidRequest(format!("session {} has no clock", session.id))
+    })?;
+
+    if to < from {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "can't move the clock backwards, from {from} to {to}"
+        )));
+    }
+
+    fill_limit_orders(conn, &session.id, from, to)?;
+    settle_resolutions(conn, &session.id, from, to)?;
+    fire_wakeups(conn, &session.id, from, to)?;
+    set_session_clock(conn, &session.id, to)?;
+
+    Ok(())
+}
+
+/// Impls GET /v0/market/[marketId]/positions, for the session's simulated users
+pub fn get_positions(
+    conn: &Connection,
+    session: &Session,
+    contract_id: &str,
+    user_id: Option<&str>,
+) -> Result<Vec<ContractMetric>, RowParsingError> {
+    let market = get_market_as_of(conn, contract_id, session.clock_time)?.ok_or_else(|| {
+        RowParsingError::MarketNotFound(format!("no market with id {contract_id}"))
+    })?;
+    let prob = market.probability.unwrap_or(0.5);
+
+    let mut bets_by_user: HashMap<String, Vec<Bet>> = HashMap::new();
+    for bet in get_sim_bets(conn, &session.id, Some(contract_id))? {
+        if user_id.is_some_and(|user_id| user_id != bet.user_id) {
+            continue;
+        }
+        bets_by_user
+            .entry(bet.user_id.clone())
+            .or_default()
+            .push(bet);
+    }
+
+    let mut metrics = Vec::new();
+    for (user_id, bets) in bets_by_user {
+        // fold instead of sum, since an empty f64 sum is -0.0
+        let yes_shares = bets
+            .iter()
+            .filter(|b| b.outcome == "YES")
+            .fold(0.0, |acc, b| acc + b.shares);
+        let no_shares = bets
+            .iter()
+            .filter(|b| b.outcome == "NO")
+            .fold(0.0, |acc, b| acc + b.shares);
+        let invested = bets.iter().fold(0.0, |acc, b| acc + b.amount);
+        let payout = position_payout(&market, yes_shares, no_shares, invested, prob);
+        let profit = payout - invested;
+
+        let max_shares_outcome = if yes_shares == 0.0 && no_shares == 0.0 {
+            None
+        } else if yes_shares >= no_shares {
+            Some("YES".to_string())
+        } else {
+            Some("NO".to_string())
+        };
+
+        let last_bet = bets.last().expect("users with positions have bets");
+
+        metrics.push(ContractMetric {
+            contract_id: contract_id.to_string(),
+            from: None,
+            has_no_shares: no_shares > 0.0,
+            has_shares: yes_shares > 0.0 || no_shares > 0.0,
+            has_yes_shares: yes_shares > 0.0,
+            invested,
+            loan: 0.0,
+            max_shares_outcome,
+            payout,
+            profit,
+            profit_percent: if invested > 0.0 {
+                100.0 * profit / invested
+            } else {
+                0.0
+            },
+            total_shares: HashMap::from([
+                (MarketOutcome::Yes, yes_shares),
+                (MarketOutcome::No, no_shares),
+            ]),
+            user_id,
+            user_name: last_bet.user_name.clone().unwrap_or_default(),
+            user_avatar_url: last_bet.user_avatar_url.clone().unwrap_or_default(),
+            last_bet_time: last_bet.created_time,
+        });
+    }
+
+    metrics.sort_by(|a, b| b.profit.total_cmp(&a.profit));
+
+    Ok(metrics)
+}
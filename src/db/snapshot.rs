@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+
+use rusqlite::Connection;
+
+use crate::data_types::{Session, SessionSnapshot, SnapshotUser};
+use crate::db::api_key_table::{get_api_keys_for_session, insert_api_key};
+use crate::db::db_common::{new_id, now_millis};
+use crate::db::errors::RowParsingError;
+use crate::db::session_table::{anchor_clock_mode, insert_session};
+use crate::db::sim_bet_table::{get_sim_bets, insert_sim_bet};
+use crate::db::user_table::{get_user, insert_user};
+use crate::db::wakeup_table::{get_wakeups, insert_wakeup};
+
+/// Where snapshot files are kept, unless configured otherwise
+pub const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+
+/// The file for a snapshot `path` given by a client, inside `snapshot_dir`. Paths
+/// can't be absolute or go up with `..`, so clients can't read or write files
+/// outside of the snapshot directory.
+pub fn snapshot_file_path(snapshot_dir: &Path, path: &str) -> Result<PathBuf, RowParsingError> {
+    let relative = Path::new(path);
+    let is_plain = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+
+    if path.is_empty() || !is_plain {
+        return Err(RowParsingError::InvalidRequest(format!(
+            "invalid snapshot path '{path}', expected a relative path without '..'"
+        )));
+    }
+
+    Ok(snapshot_dir.join(relative))
+}
+
+/// A 400 if the client's `path` doesn't lead to a file, or to one that's
+/// text, since that's theirs to fix, and a 500 for anything else, like the
+/// disk being full. A file where `path` needs a directory is AlreadyExists.
+fn file_error(action: &str, path: &str, e: io::Error) -> RowParsingError {
+    let message = format!("couldn't {action} {path}: {e}");
+    match e.kind() {
+        ErrorKind::NotFound
+        | ErrorKind::NotADirectory
+        | ErrorKind::IsADirectory
+        | ErrorKind::AlreadyExists
+        | ErrorKind::InvalidData => RowParsingError::InvalidRequest(message),
+        _ => RowParsingError::Generic(message),
+    }
+}
+
+/// Writes the snapshot to `file_path`, the file for the client's `path`. It's
+/// written to a temporary file next to it first, so a failed save never
+/// leaves a half-written snapshot, or clobbers an older one.
+pub fn write_snapshot_file(
+    file_path: &Path,
+    path: &str,
+    snapshot: &SessionSnapshot,
+) -> Result<(), RowParsingError> {
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| file_error("create", path, e))?;
+    }
+
+    let mut temp_path = file_path.as_os_str().to_owned();
+    temp_path.push(format!(".{}.tmp", new_id()));
+    let temp_path = PathBuf::from(temp_path);
+
+    let written = File::create(&temp_path)
+        .map_err(|e| file_error("create", path, e))
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer(&mut writer, snapshot)?;
+            writer.flush().map_err(|e| file_error("write", path, e))?;
+            fs::rename(&temp_path, file_path).map_err(|e| file_error("write", path, e))
+        });
+
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+/// Reads the snapshot in `file_path`, the file for the client's `path`
+pub fn read_snapshot_file(
+    file_path: &Path,
+    path: &str,
+) -> Result<SessionSnapshot, RowParsingError> {
+    let contents = fs::read_to_string(file_path).map_err(|e| file_error("read", path, e))?;
+    serde_json::from_str(&contents).map_err(|e| {
+        RowParsingError::InvalidRequest(format!("{path} isn't a session snapshot: {e}"))
+    })
+}
+
+/// Captures everything in a session, so it can be recreated later
+pub fn snapshot_session(
+    conn: &Connection,
+    session: &Session,
+) -> Result<SessionSnapshot, RowParsingError> {
+    let mut users = Vec::new();
+    for (api_key, user_id) in get_api_keys_for_session(conn, &session.id)? {
+        let user = get_user(conn, &user_id)?
+            .ok_or_else(|| RowParsingError::UserNotFound(format!("no user with id {user_id}")))?;
+        users.push(SnapshotUser { api_key, user });
+    }
+
+    Ok(SessionSnapshot {
+        clock_time: session.clock_time,
+        clock_mode: session.clock_mode.clone(),
+        users,
+        bets: get_sim_bets(conn, &session.id, None)?,
+        wakeups: get_wakeups(conn, &session.id)?,
+    })
+}
+
+/// Creates a new session from a snapshot. Users, bets and wakeups get new ids, since
+/// ids are unique across sessions, but api keys stay the same, so bots can
+/// carry on in the new session without noticing.
+pub fn restore_session(
+    conn: &Connection,
+    snapshot: &SessionSnapshot,
+) -> Result<Session, RowParsingError> {
+    let session = Session {
+        id: new_id(),
+        created_time: now_millis(),
+        clock_time: snapshot.clock_time,
+        clock_mode: anchor_clock_mode(snapshot.clock_mode.clone(), snapshot.clock_time),
+    };
+    insert_session(conn, &session)?;
+
+    // old user id -> new user id
+    let mut user_ids: HashMap<&str, String> = HashMap::new();
+    for snapshot_user in &snapshot.users {
+        let mut user = snapshot_user.user.clone();
+        user.id = new_id();
+        user_ids.insert(&snapshot_user.user.id, user.id.clone());
+
+        insert_api_key(conn, &session.id, &snapshot_user.api_key, &user.id)?;
+        insert_user(conn, user)?;
+    }
+
+    for bet in &snapshot.bets {
+        let mut bet = bet.clone();
+        bet.id = new_id();
+        bet.user_id = user_ids
+            .get(bet.user_id.as_str())
+            .ok_or_else(|| {
+                RowParsingError::InvalidRequest(format!(
+                    "snapshot has a bet by {}, who isn't one of its users",
+                    bet.user_id
+                ))
+            })?
+            .clone();
+
+        insert_sim_bet(conn, &session.id, &bet)?;
+    }
+
+    for wakeup in &snapshot.wakeups {
+        let mut wakeup = wakeup.clone();
+        wakeup.id = new_id();
+        insert_wakeup(conn, &session.id, &wakeup)?;
+    }
+
+    Ok(session)
+}
@@ -0,0 +1,182 @@
+//! A small made-up backtest db for tests, so they don't need the backtest data.
+//! It's kept in a temporary file, which is removed when the fixture is dropped.
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::json;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::data_types::{Bet, LiteMarket};
+use crate::db::answer_table::{create_answer_table, init_answer_table};
+use crate::db::api_key_table::init_api_key_table;
+use crate::db::bet_table::{bulk_insert_bets, create_bet_table, init_bet_table};
+use crate::db::db_common::{get_db_connection, new_id};
+use crate::db::group_table::init_group_table;
+use crate::db::historical_user_table::init_historical_user_table;
+use crate::db::market_table::{
+    bulk_insert_markets, create_market_search_table, create_market_table, init_market_table,
+};
+use crate::db::session_table::init_session_table;
+use crate::db::sim_bet_table::init_sim_bet_table;
+use crate::db::user_table::init_user_table;
+use crate::db::wakeup_table::init_wakeup_table;
+use crate::db::webhook_table::init_webhook_table;
+
+/// When the first markets are created
+pub const START: u64 = 1_680_000_000_000;
+pub const HOUR: u64 = 60 * 60 * 1000;
+pub const DAY: u64 = 24 * HOUR;
+
+/// Markets are created in pairs with the same created time, and share their
+/// volumes and liquidities with others, so that sorts have ties to break.
+/// Every third market never closes, and market05 resolves YES on day 10.
+pub fn markets() -> Vec<LiteMarket> {
+    (0..12u64)
+        .map(|i| {
+            let created_time = START + (i / 2) * HOUR;
+            let resolution_time = (i == 5).then_some(START + 10 * DAY);
+            let market = json!({
+                "id": format!("market{i:02}"),
+                "creatorId": format!("user{}", i % 3),
+                "creatorUsername": format!("user{}", i % 3),
+                "creatorName": format!("User {}", i % 3),
+                "creatorAvatarUrl": null,
+                "closeTime": (i % 3 != 0).then_some(START + (20 + i % 4) * DAY),
+                "createdTime": created_time,
+                "question": format!("Will market {i} happen?"),
+                "url": format!("https://manifold.markets/user{}/market-{i}", i % 3),
+                "outcomeType": "BINARY",
+                "mechanism": "cpmm-1",
+                "probability": 0.5,
+                "pool": { "YES": 100.0, "NO": 100.0 },
+                "p": 0.5,
+                "total_liquidity": ((i % 3) * 10) as f64,
+                "volume": ((i % 4) * 100) as f64,
+                "volume24Hours": 0.0,
+                "isResolved": resolution_time.is_some(),
+                "resolutionTime": resolution_time,
+                "resolution": resolution_time.map(|_| "YES"),
+                "lastUpdatedTime": created_time,
+                "lastBetTime": null,
+            });
+            serde_json::from_value(market).unwrap()
+        })
+        .collect()
+}
+
+/// Bets come in pairs with the same created time, every 10 minutes from hour
+/// 6, each moving its market up 5 points from wherever it was. Every eighth bet is a limit order that fills in two halves, one and two
+/// hours after it's placed, and the ones four after those are limit orders
+/// that never fill.
+pub fn bets() -> Vec<Bet> {
+    (0..48u64)
+        .map(|i| {
+            let created_time = START + 6 * HOUR + (i / 2) * 10 * 60 * 1000 + 7 * 60 * 1000;
+            let mut bet = json!({
+                "id": format!("bet{i:02}"),
+                "userId": format!("user{}", i % 3),
+                "userUsername": format!("user{}", i % 3),
+                "userName": format!("User {}", i % 3),
+                "contractId": format!("market{:02}", i % 12),
+                "createdTime": created_time,
+                "amount": (10 + i) as f64,
+                "outcome": if i % 2 == 0 { "YES" } else { "NO" },
+                "shares": (10 + i) as f64,
+                "probBefore": 0.3 + (i % 5) as f64 * 0.1,
+                "probAfter": 0.35 + (i % 5) as f64 * 0.1,
+                "isAnte": false,
+                "isRedemption": false,
+                "isChallenge": false,
+                "visibility": "public",
+            });
+
+            let fill = |hours: u64| {
+                json!({
+                    "timestamp": created_time + hours * HOUR,
+                    "matchedBetId": null,
+                    "amount": 25.0,
+                    "shares": 50.0,
+                })
+            };
+            let limit_props = match i % 8 {
+                0 => Some(json!({
+                    "orderAmount": 50.0,
+                    "limitProb": 0.4,
+                    "isFilled": true,
+                    "isCancelled": false,
+                    "fills": [fill(1), fill(2)],
+                })),
+                4 => Some(json!({
+                    "orderAmount": 50.0,
+                    "limitProb": 0.3,
+                    "isFilled": false,
+                    "isCancelled": false,
+                    "fills": [],
+                })),
+                _ => None,
+            };
+            // a limit order's amount and shares are what's filled of it
+            if let Some(limit_props) = limit_props {
+                let filled = limit_props["fills"].as_array().unwrap().len() as f64;
+                let bet = bet.as_object_mut().unwrap();
+                bet.insert("amount".to_string(), json!(25.0 * filled));
+                bet.insert("shares".to_string(), json!(50.0 * filled));
+                bet.extend(limit_props.as_object().unwrap().clone());
+            }
+
+            serde_json::from_value(bet).unwrap()
+        })
+        .collect()
+}
+
+pub struct Fixture {
+    pub connection_pool: Arc<Pool<SqliteConnectionManager>>,
+    path: PathBuf,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("mmmbacktest-test-{}.db", new_id()));
+        let connection_pool = Arc::new(
+            Pool::new(SqliteConnectionManager::file(&path)).expect("failed to open the test db"),
+        );
+
+        let mut conn = get_db_connection(connection_pool.clone());
+
+        // the tables that would otherwise be filled from the backtest data
+        create_market_table(&conn).unwrap();
+        bulk_insert_markets(&mut conn, &markets()).unwrap();
+        create_market_search_table(&conn).unwrap();
+        create_answer_table(&conn).unwrap();
+        create_bet_table(&conn).unwrap();
+        bulk_insert_bets(&mut conn, &bets()).unwrap();
+
+        init_market_table(&mut conn).unwrap();
+        init_answer_table(&mut conn).unwrap();
+        init_group_table(&mut conn).unwrap();
+        init_bet_table(&mut conn).unwrap();
+        init_user_table(&mut conn).unwrap();
+        init_historical_user_table(&mut conn).unwrap();
+        init_session_table(&mut conn).unwrap();
+        init_api_key_table(&mut conn).unwrap();
+        init_sim_bet_table(&mut conn).unwrap();
+        init_wakeup_table(&mut conn).unwrap();
+        init_webhook_table(&mut conn).unwrap();
+
+        Fixture {
+            connection_pool,
+            path,
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
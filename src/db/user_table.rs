@@ -1,5 +1,5 @@
 use log::debug;
-use rusqlite::{params, Connection, Result, Row};
+use rusqlite::{named_params, params, Connection, OptionalExtension, Result, Row};
 use std::collections::HashMap;
 
 use crate::data_types::{TimePeriod, User};
@@ -8,6 +8,9 @@ use crate::db::errors::RowParsingError;
 
 pub const DEFAULT_USER_ID: &str = "this is the default user id";
 
+/// Mana given to the default user, and to api key users unless configured otherwise
+pub const DEFAULT_STARTING_BALANCE: f64 = 1000.0;
+
 pub fn create_users_table(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE users (
@@ -38,7 +41,7 @@ pub fn create_users_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
-pub fn insert_user(conn: &mut Connection, user: User) -> Result<usize> {
+pub fn insert_user(conn: &Connection, user: User) -> Result<usize> {
     let stmt_str = "INSERT INTO users (
             id, created_time, name, username, url, avatar_url, bio, banner_url, website,
             twitter_handle, discord_handle, is_bot, is_admin, is_trustworthy,
@@ -50,37 +53,30 @@ pub fn insert_user(conn: &mut Connection, user: User) -> Result<usize> {
             ?19, ?20, ?21
         )";
 
-    let tx = conn.transaction()?;
-
-    {
-        let mut stmt = tx.prepare(stmt_str)?;
-        stmt.execute(params![
-            user.id,
-            user.created_time,
-            user.name,
-            user.username,
-            user.url,
-            user.avatar_url,
-            user.bio,
-            user.banner_url,
-            user.website,
-            user.twitter_handle,
-            user.discord_handle,
-            user.is_bot,
-            user.is_admin,
-            user.is_trustworthy,
-            user.is_banned_from_posting,
-            user.user_deleted,
-            user.balance,
-            user.total_deposits,
-            user.last_bet_time,
-            user.current_betting_streak,
-            serde_json::to_string(&user.profit_cached).unwrap(),
-        ])?;
-    }
-    tx.commit()?;
-
-    Ok(1)
+    let mut stmt = conn.prepare(stmt_str)?;
+    stmt.execute(params![
+        user.id,
+        user.created_time,
+        user.name,
+        user.username,
+        user.url,
+        user.avatar_url,
+        user.bio,
+        user.banner_url,
+        user.website,
+        user.twitter_handle,
+        user.discord_handle,
+        user.is_bot,
+        user.is_admin,
+        user.is_trustworthy,
+        user.is_banned_from_posting,
+        user.user_deleted,
+        user.balance,
+        user.total_deposits,
+        user.last_bet_time,
+        user.current_betting_streak,
+        serde_json::to_string(&user.profit_cached).unwrap(),
+    ])
 }
 
 pub fn rusqlite_row_to_user(row: &Row) -> Result<User, RowParsingError> {
@@ -112,6 +108,33 @@ pub fn rusqlite_row_to_user(row: &Row) -> Result<User, RowParsingError> {
     })
 }
 
+pub fn get_user(conn: &Connection, id: &str) -> Result<Option<User>, RowParsingError> {
+    let maybe_user = conn
+        .query_row(
+            "SELECT * FROM users WHERE id = :id",
+            named_params! { ":id": id },
+            |row| Ok(rusqlite_row_to_user(row)),
+        )
+        .optional()?;
+
+    maybe_user.transpose()
+}
+
+/// Adds `amount` (which can be negative) to a user's balance
+pub fn add_to_balance(conn: &Connection, id: &str, amount: f64) -> Result<usize> {
+    conn.execute(
+        "UPDATE users SET balance = balance + ?2 WHERE id = ?1",
+        params![id, amount],
+    )
+}
+
+pub fn set_last_bet_time(conn: &Connection, id: &str, last_bet_time: u64) -> Result<usize> {
+    conn.execute(
+        "UPDATE users SET last_bet_time = ?2 WHERE id = ?1",
+        params![id, last_bet_time],
+    )
+}
+
 pub fn get_default_backtest_user() -> User {
     User {
         id: DEFAULT_USER_ID.to_string(),
@@ -131,7 +154,7 @@ pub fn get_default_backtest_user() -> User {
         is_trustworthy: Some(false),
         is_banned_from_posting: Some(true),
         user_deleted: Some(false),
-        balance: 1000.0,
+        balance: DEFAULT_STARTING_BALANCE,
         total_deposits: 0.0,
         last_bet_time: Some(1700000000001),
         current_betting_streak: Some(0),
@@ -144,6 +167,44 @@ pub fn get_default_backtest_user() -> User {
     }
 }
 
+/// A fresh simulated user, handed out the first time an unknown api key shows up.
+/// `created_time` is the session clock, so the user doesn't show up from the future.
+pub fn get_new_backtest_user(
+    id: &str,
+    username: &str,
+    starting_balance: f64,
+    created_time: u64,
+) -> User {
+    User {
+        id: id.to_string(),
+        created_time,
+        name: username.to_string(),
+        username: username.to_string(),
+        url: None,
+        avatar_url: get_default_backtest_user().avatar_url,
+        bio: None,
+        banner_url: None,
+        website: None,
+        twitter_handle: None,
+        discord_handle: None,
+        is_bot: Some(true),
+        is_admin: Some(false),
+        is_trustworthy: Some(false),
+        is_banned_from_posting: Some(true),
+        user_deleted: Some(false),
+        balance: starting_balance,
+        total_deposits: starting_balance,
+        last_bet_time: None,
+        current_betting_streak: Some(0),
+        profit_cached: HashMap::<TimePeriod, f64>::from([
+            (TimePeriod::Daily, 0.0),
+            (TimePeriod::Weekly, 0.0),
+            (TimePeriod::Monthly, 0.0),
+            (TimePeriod::AllTime, 0.0),
+        ]),
+    }
+}
+
 pub fn init_user_table(conn: &mut Connection) -> Result<usize> {
     if !db_common::table_exists(conn, "users")? {
         debug!("creating 'users' table");
@@ -0,0 +1,126 @@
+use log::debug;
+use rusqlite::{named_params, params, Connection, Result, Row};
+
+use crate::data_types::Wakeup;
+use crate::db::db_common;
+use crate::db::errors::RowParsingError;
+
+pub fn create_wakeup_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE wakeups (
+            id TEXT PRIMARY KEY,
+            session_id TEXT NOT NULL,
+            created_time BIGINT NOT NULL,
+            at BIGINT,
+            market_id TEXT,
+            points FLOAT,
+            reference_probability FLOAT,
+            fired_time BIGINT
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn insert_wakeup(conn: &Connection, session_id: &str, wakeup: &Wakeup) -> Result<usize> {
+    conn.execute(
+        "INSERT INTO wakeups (
+            id, session_id, created_time, at, market_id, points, reference_probability, fired_time
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            wakeup.id,
+            session_id,
+            wakeup.created_time,
+            wakeup.at,
+            wakeup.market_id,
+            wakeup.points,
+            wakeup.reference_probability,
+            wakeup.fired_time,
+        ],
+    )
+}
+
+pub fn rusqlite_row_to_wakeup(row: &Row) -> Result<Wakeup, RowParsingError> {
+    Ok(Wakeup {
+        id: row.get(0)?,
+        created_time: row.get(2)?,
+        at: row.get(3)?,
+        market_id: row.get(4)?,
+        points: row.get(5)?,
+        reference_probability: row.get(6)?,
+        fired_time: row.get(7)?,
+    })
+}
+
+pub fn get_wakeups(conn: &Connection, session_id: &str) -> Result<Vec<Wakeup>, RowParsingError> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM wakeups WHERE session_id = :session_id ORDER BY created_time ASC, id ASC",
+    )?;
+
+    let wakeup_iter = stmt.query_map(named_params! { ":session_id": session_id }, |row| {
+        Ok(rusqlite_row_to_wakeup(row))
+    })?;
+
+    let mut wakeups = Vec::new();
+    for maybe_wakeup in wakeup_iter {
+        wakeups.push(maybe_wakeup??);
+    }
+
+    Ok(wakeups)
+}
+
+pub fn delete_wakeup(conn: &Connection, session_id: &str, id: &str) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM wakeups WHERE session_id = ?1 AND id = ?2",
+        params![session_id, id],
+    )
+}
+
+/// Fires every wakeup in the session that's due in (`from`, `to`]: time
+/// wakeups at or before `to`, and market wakeups whose market moved far enough.
+pub fn fire_wakeups(conn: &Connection, session_id: &str, from: u64, to: u64) -> Result<usize> {
+    let fired_at_time = conn.execute(
+        "UPDATE wakeups SET fired_time = at
+        WHERE session_id = ?1 AND fired_time IS NULL AND at <= ?2",
+        params![session_id, to],
+    )?;
+
+    let fired_on_move = conn.execute(
+        "UPDATE wakeups SET fired_time = (
+            SELECT MIN(created_time) FROM bets
+            WHERE contract_id = wakeups.market_id AND answer_id IS NULL
+              AND created_time > ?2 AND created_time <= ?3
+              AND ABS(prob_after - wakeups.reference_probability) * 100 > wakeups.points
+        )
+        WHERE session_id = ?1 AND fired_time IS NULL AND market_id IS NOT NULL
+          AND EXISTS (
+            SELECT 1 FROM bets
+            WHERE contract_id = wakeups.market_id AND answer_id IS NULL
+              AND created_time > ?2 AND created_time <= ?3
+              AND ABS(prob_after - wakeups.reference_probability) * 100 > wakeups.points
+          )",
+        params![session_id, from, to],
+    )?;
+
+    Ok(fired_at_time + fired_on_move)
+}
+
+pub fn init_wakeup_table(conn: &mut Connection) -> Result<usize> {
+    if !db_common::table_exists(conn, "wakeups")? {
+        debug!("creating 'wakeups' table");
+        create_wakeup_table(conn)?;
+    } else {
+        debug!("found 'wakeups' table");
+    }
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS wakeups_index ON wakeups (session_id, fired_time);",
+        [],
+    )?;
+
+    let num_rows =
+        db_common::count_rows(conn, "wakeups").expect("failed to count rows in wakeups table");
+    debug!("{num_rows} wakeups found");
+
+    Ok(num_rows)
+}
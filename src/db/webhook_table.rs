@@ -0,0 +1,98 @@
+use log::debug;
+use rusqlite::{named_params, params, Connection, Result, Row};
+
+use crate::data_types::{Webhook, WebhookEvent};
+use crate::db::db_common;
+use crate::db::errors::RowParsingError;
+
+pub fn create_webhook_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE webhooks (
+            id TEXT PRIMARY KEY,
+            session_id TEXT NOT NULL,
+            url TEXT NOT NULL,
+            events TEXT NOT NULL,
+            market_ids TEXT NOT NULL,
+            created_time BIGINT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn insert_webhook(conn: &Connection, session_id: &str, webhook: &Webhook) -> Result<usize> {
+    conn.execute(
+        "INSERT INTO webhooks (id, session_id, url, events, market_ids, created_time)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            webhook.id,
+            session_id,
+            webhook.url,
+            serde_json::to_string(&webhook.events).unwrap(),
+            serde_json::to_string(&webhook.market_ids).unwrap(),
+            webhook.created_time,
+        ],
+    )
+}
+
+/// (session id, webhook)
+pub fn rusqlite_row_to_webhook(row: &Row) -> Result<(String, Webhook), RowParsingError> {
+    let events_str: String = row.get(3)?;
+    let market_ids_str: String = row.get(4)?;
+
+    Ok((
+        row.get(1)?,
+        Webhook {
+            id: row.get(0)?,
+            url: row.get(2)?,
+            events: serde_json::from_str::<Vec<WebhookEvent>>(&events_str)?,
+            market_ids: serde_json::from_str::<Vec<String>>(&market_ids_str)?,
+            created_time: row.get(5)?,
+        },
+    ))
+}
+
+/// Every session's webhooks if `session_id` is None, as (session id, webhook) pairs
+pub fn get_webhooks(
+    conn: &Connection,
+    session_id: Option<&str>,
+) -> Result<Vec<(String, Webhook)>, RowParsingError> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM webhooks
+        WHERE :session_id IS NULL OR session_id = :session_id
+        ORDER BY created_time ASC, id ASC",
+    )?;
+
+    let webhook_iter = stmt.query_map(named_params! { ":session_id": session_id }, |row| {
+        Ok(rusqlite_row_to_webhook(row))
+    })?;
+
+    let mut webhooks = Vec::new();
+    for maybe_webhook in webhook_iter {
+        webhooks.push(maybe_webhook??);
+    }
+
+    Ok(webhooks)
+}
+
+pub fn delete_webhook(conn: &Connection, session_id: &str, id: &str) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM webhooks WHERE session_id = ?1 AND id = ?2",
+        params![session_id, id],
+    )
+}
+
+pub fn init_webhook_table(conn: &mut Connection) -> Result<usize> {
+    if !db_common::table_exists(conn, "webhooks")? {
+        debug!("creating 'webhooks' table");
+        create_webhook_table(conn)?;
+    } else {
+        debug!("found 'webhooks' table");
+    }
+
+    let num_rows =
+        db_common::count_rows(conn, "webhooks").expect("failed to count rows in webhooks table");
+    debug!("{num_rows} webhooks found");
+
+    Ok(num_rows)
+}
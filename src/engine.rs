@@ -0,0 +1,476 @@
+//! The backtest as a library. BacktestEngine has a method for each Manifold
+//! endpoint the server implements, plus the session controls a backtest needs,
+//! so a Rust bot can run against the backtest data in-process instead of over
+//! HTTP. The server in server.rs is a thin layer over it.
+//!
+//! Methods that take a `session_id` run in that session, or in the default
+//! session if it's None, like requests without the session header. Methods
+//! that take an `api_key` act as the simulated user behind it, like requests
+//! with an `Authorization: Key <api key>` header.
+
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::data_types::{
+    AnyUser, Bet, BetQuery, BetRequest, ClockMode, ContractMetric, FullMarket, HistoricalGroup,
+    HistoricalUser, LeaderboardEntry, LeaderboardMetric, LiteMarket, MarketQuery,
+    SearchMarketsQuery, Session, User, Wakeup, Webhook, WebhookEvent,
+};
+use crate::db;
+use crate::db::db_common::{setup_db, try_get_db_connection};
+use crate::db::{
+    EventCursor, EventFilter, RowParsingError, SessionEvent, DEFAULT_SESSION_ID,
+    DEFAULT_SNAPSHOT_DIR,
+};
+use crate::event_driven::{self, Activity};
+use crate::lockstep::{self, Lockstep};
+use crate::realtime;
+use crate::webhooks::Webhooks;
+
+#[derive(Clone)]
+pub struct BacktestEngine {
+    connection_pool: Arc<Pool<SqliteConnectionManager>>,
+    /// mana that each new api key user starts out with
+    starting_balance: f64,
+    /// where session snapshots are saved to and restored from
+    snapshot_dir: PathBuf,
+    /// the bots registered with lockstep sessions
+    lockstep: Arc<Lockstep>,
+    /// the tasks sending each session's events to its webhooks
+    webhooks: Arc<Webhooks>,
+}
+
+impl BacktestEngine {
+    /// Opens mmmbacktest.db in the working directory, building it from the
+    /// backtest data the first time
+    pub fn new(starting_balance: f64) -> Result<Self, RowParsingError> {
+        let connection_pool = setup_db()?;
+        Ok(BacktestEngine {
+            webhooks: Arc::new(Webhooks::new(connection_pool.clone())),
+            connection_pool,
+            starting_balance,
+            snapshot_dir: PathBuf::from(DEFAULT_SNAPSHOT_DIR),
+            lockstep: Arc::new(Lockstep::default()),
+        })
+    }
+
+    /// Keeps session snapshots in `snapshot_dir`, instead of ./snapshots
+    pub fn with_snapshot_dir(mut self, snapshot_dir: impl Into<PathBuf>) -> Self {
+        self.snapshot_dir = snapshot_dir.into();
+        self
+    }
+
+    /// Runs the realtime, lockstep and event-driven clocks, and the webhooks
+    /// already in the db, on the current tokio runtime. `activity` is what the
+    /// event-driven clocks wait on.
+    pub(crate) fn start_background_tasks(&self, activity: Arc<Activity>) {
+        tokio::spawn(lockstep::run_timeouts(
+            self.lockstep.clone(),
+            self.connection_pool.clone(),
+        ));
+        tokio::spawn(realtime::run_realtime_clocks(self.connection_pool.clone()));
+        tokio::spawn(event_driven::run_event_driven_clocks(
+            activity,
+            self.connection_pool.clone(),
+        ));
+        self.webhooks.start_all();
+    }
+
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>, RowParsingError> {
+        try_get_db_connection(self.connection_pool.clone())
+    }
+
+    /// A connection, and the session `session_id` or the default session
+    fn conn_and_session(
+        &self,
+        session_id: Option<&str>,
+    ) -> Result<(PooledConnection<SqliteConnectionManager>, Session), RowParsingError> {
+        let conn = self.conn()?;
+        let session = db::get_session(&conn, session_id.unwrap_or(DEFAULT_SESSION_ID))?;
+        Ok((conn, session))
+    }
+
+    /// The session `session_id`, or the default session
+    pub fn get_session(&self, session_id: Option<&str>) -> Result<Session, RowParsingError> {
+        Ok(self.conn_and_session(session_id)?.1)
+    }
+
+    /// Starts a session with its clock at `start_time`, or at the first market
+    pub fn create_session(
+        &self,
+        start_time: Option<u64>,
+        clock_mode: ClockMode,
+    ) -> Result<Session, RowParsingError> {
+        let conn = self.conn()?;
+        db::create_session(&conn, start_time, clock_mode)
+    }
+
+    pub fn delete_session(&self, session_id: &str) -> Result<(), RowParsingError> {
+        let conn = self.conn()?;
+        db::delete_session(&conn, session_id)
+    }
+
+    /// Moves the session's clock to `to`, or forward `by` ms. Exactly one of
+    /// them has to be given, and `by` needs a session with a clock.
+    pub fn advance_clock(
+        &self,
+        session_id: &str,
+        to: Option<u64>,
+        by: Option<u64>,
+    ) -> Result<Session, RowParsingError> {
+        let conn = self.conn()?;
+        let session = db::get_session(&conn, session_id)?;
+
+        let to = match (to, by, session.clock_time) {
+            (Some(to), None, _) => to,
+            (None, Some(by), Some(clock_time)) => clock_time + by,
+            _ => {
+                return Err(RowParsingError::InvalidRequest(
+                    "give exactly one of 'to' or 'by', on a session with a clock".to_string(),
+                ))
+            }
+        };
+
+        db::advance_session_clock(&conn, session_id, to)
+    }
+
+    /// Changes how the session's clock moves
+    pub fn set_clock_mode(
+        &self,
+        session_id: &str,
+        clock_mode: &ClockMode,
+    ) -> Result<Session, RowParsingError> {
+        let conn = self.conn()?;
+        db::set_clock_mode(&conn, session_id, clock_mode)
+    }
+
+    /// Saves the session to `path`, relative to the snapshot directory
+    pub fn save_snapshot(&self, session_id: &str, path: &str) -> Result<(), RowParsingError> {
+        let conn = self.conn()?;
+        db::save_session_snapshot(&conn, session_id, &self.snapshot_dir, path)
+    }
+
+    /// Creates a new session from the snapshot at `path`, relative to the
+    /// snapshot directory
+    pub fn restore_snapshot(&self, path: &str) -> Result<Session, RowParsingError> {
+        let conn = self.conn()?;
+        db::restore_session_snapshot(&conn, &self.snapshot_dir, path)
+    }
+
+    /// Creates a new session that copies this one, to try something out from here
+    pub fn fork_session(&self, session_id: &str) -> Result<Session, RowParsingError> {
+        let conn = self.conn()?;
+        db::fork_session(&conn, session_id)
+    }
+
+    /// Ranks the historical users up to the session clock
+    pub fn get_leaderboard(
+        &self,
+        session_id: &str,
+        metric: LeaderboardMetric,
+        days: Option<u64>,
+        min_bets: Option<u64>,
+        limit: Option<i64>,
+    ) -> Result<Vec<LeaderboardEntry>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(Some(session_id))?;
+        db::get_leaderboard(&conn, session.clock_time, metric, days, min_bets, limit)
+    }
+
+    /// Sets a stop point for the event-driven clock, either at time `at` or for
+    /// when market `market_id` moves more than `points` percentage points
+    pub fn add_wakeup(
+        &self,
+        session_id: &str,
+        at: Option<u64>,
+        market_id: Option<&str>,
+        points: Option<f64>,
+    ) -> Result<Wakeup, RowParsingError> {
+        let conn = self.conn()?;
+        db::add_wakeup(&conn, session_id, at, market_id, points)
+    }
+
+    /// The session's wakeups, including the ones that fired
+    pub fn get_wakeups(&self, session_id: &str) -> Result<Vec<Wakeup>, RowParsingError> {
+        let conn = self.conn()?;
+        db::get_wakeups(&conn, session_id)
+    }
+
+    pub fn delete_wakeup(&self, session_id: &str, wakeup_id: &str) -> Result<(), RowParsingError> {
+        let conn = self.conn()?;
+        db::delete_wakeup(&conn, session_id, wakeup_id)
+    }
+
+    /// Adds a webhook that's sent the session's events from now on. The events
+    /// are sent from a tokio task, so this has to be called inside a tokio runtime.
+    pub fn create_webhook(
+        &self,
+        session_id: &str,
+        url: &str,
+        events: Vec<WebhookEvent>,
+        market_ids: Vec<String>,
+    ) -> Result<Webhook, RowParsingError> {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return Err(RowParsingError::Generic(
+                "webhooks can only be added inside a tokio runtime".to_string(),
+            ));
+        }
+
+        // take the cursor first, so nothing that happens while adding it is missed
+        let (session, cursor) = self.event_cursor(Some(session_id))?;
+        let conn = self.conn()?;
+        let webhook = db::add_webhook(&conn, &session.id, url, events, market_ids)?;
+        self.webhooks.start(&session.id, webhook.clone(), cursor);
+        Ok(webhook)
+    }
+
+    pub fn list_webhooks(&self, session_id: &str) -> Result<Vec<Webhook>, RowParsingError> {
+        let conn = self.conn()?;
+        db::get_webhooks(&conn, session_id)
+    }
+
+    /// Removes the webhook. Its task stops sending to it within a read or two.
+    pub fn delete_webhook(
+        &self,
+        session_id: &str,
+        webhook_id: &str,
+    ) -> Result<(), RowParsingError> {
+        let conn = self.conn()?;
+        db::delete_webhook(&conn, session_id, webhook_id)
+    }
+
+    /// Registers the bot with a lockstep session, so the clock waits for it
+    pub fn register_bot(&self, session_id: &str, bot_id: &str) -> Result<(), RowParsingError> {
+        let conn = self.conn()?;
+        self.lockstep
+            .register(&conn, session_id, bot_id)
+            .map_err(RowParsingError::InvalidRequest)
+    }
+
+    /// Unregisters the bot. The bot that left might have been the one everyone
+    /// was waiting for, so this can move the clock.
+    pub fn unregister_bot(&self, session_id: &str, bot_id: &str) -> Result<(), RowParsingError> {
+        let conn = self.conn()?;
+        self.lockstep
+            .unregister(&conn, session_id, bot_id)
+            .map_err(RowParsingError::InvalidRequest)
+    }
+
+    /// Marks the bot as ready, and waits for the clock to move to the next
+    /// event, which happens once every bot in the session is ready or the round
+    /// times out. Returns the session as of then.
+    pub async fn bot_ready(
+        &self,
+        session_id: &str,
+        bot_id: &str,
+    ) -> Result<Session, RowParsingError> {
+        // getting ready can move the clock, so this runs on the blocking pool
+        let maybe_advanced = tokio::task::spawn_blocking({
+            let engine = self.clone();
+            let (session_id, bot_id) = (session_id.to_string(), bot_id.to_string());
+            move || {
+                let conn = engine.conn()?;
+                engine
+                    .lockstep
+                    .ready(&conn, &session_id, &bot_id)
+                    .map_err(RowParsingError::InvalidRequest)
+            }
+        })
+        .await
+        .map_err(|e| RowParsingError::Generic(e.to_string()))?;
+        let mut advanced = maybe_advanced?;
+
+        // an error means the round was dropped, and the session with it
+        let _ = advanced.changed().await;
+
+        self.get_session(Some(session_id))
+    }
+
+    /// The session, and a cursor at its current state for reading its events
+    /// from, for the websocket
+    pub(crate) fn event_cursor(
+        &self,
+        session_id: Option<&str>,
+    ) -> Result<(Session, EventCursor), RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        let cursor = db::event_cursor(&conn, &session)?;
+        Ok((session, cursor))
+    }
+
+    /// The session's events since `cursor` that `filter` wants, and the cursor
+    /// to read the next ones from
+    pub(crate) fn get_events_since(
+        &self,
+        session_id: &str,
+        cursor: EventCursor,
+        filter: &EventFilter,
+    ) -> Result<(Vec<SessionEvent>, EventCursor), RowParsingError> {
+        let (conn, session) = self.conn_and_session(Some(session_id))?;
+        db::get_events_since(&conn, &session, cursor, filter)
+    }
+
+    /// GET /v0/markets
+    pub fn get_markets(
+        &self,
+        session_id: Option<&str>,
+        query: &MarketQuery,
+    ) -> Result<Vec<LiteMarket>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_markets(&conn, &session, query)
+    }
+
+    /// GET /v0/search-markets
+    pub fn search_markets(
+        &self,
+        session_id: Option<&str>,
+        query: &SearchMarketsQuery,
+    ) -> Result<Vec<LiteMarket>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::search_markets(&conn, &session, query)
+    }
+
+    /// GET /v0/market/[id]
+    pub fn get_market(
+        &self,
+        session_id: Option<&str>,
+        id: &str,
+    ) -> Result<FullMarket, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_full_market(&conn, &session, id)
+    }
+
+    /// GET /v0/slug/[slug]
+    pub fn get_market_by_slug(
+        &self,
+        session_id: Option<&str>,
+        slug: &str,
+    ) -> Result<FullMarket, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_market_by_slug(&conn, &session, slug)
+    }
+
+    /// GET /v0/market/[id]/positions
+    pub fn get_positions(
+        &self,
+        session_id: Option<&str>,
+        market_id: &str,
+        user_id: Option<&str>,
+    ) -> Result<Vec<ContractMetric>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_positions(&conn, &session, market_id, user_id)
+    }
+
+    /// GET /v0/bets
+    pub fn get_bets(
+        &self,
+        session_id: Option<&str>,
+        query: &BetQuery,
+    ) -> Result<Vec<Bet>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_bets(&conn, &session, query)
+    }
+
+    /// POST /v0/bet
+    pub fn place_bet(
+        &self,
+        session_id: Option<&str>,
+        api_key: Option<&str>,
+        bet: &BetRequest,
+    ) -> Result<Bet, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::place_bet(&conn, &session, api_key, self.starting_balance, bet)
+    }
+
+    /// POST /v0/bet/cancel/[id]
+    pub fn cancel_bet(
+        &self,
+        session_id: Option<&str>,
+        api_key: Option<&str>,
+        bet_id: &str,
+    ) -> Result<Bet, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::cancel_bet(&conn, &session, api_key, self.starting_balance, bet_id)
+    }
+
+    /// GET /v0/me
+    pub fn get_me(
+        &self,
+        session_id: Option<&str>,
+        api_key: Option<&str>,
+    ) -> Result<User, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_me(&conn, &session, api_key, self.starting_balance)
+    }
+
+    /// GET /v0/user/[username]
+    pub fn get_user(
+        &self,
+        session_id: Option<&str>,
+        username: &str,
+    ) -> Result<AnyUser, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_user(&conn, &session, None, Some(username))
+    }
+
+    /// GET /v0/user/by-id/[id]
+    pub fn get_user_by_id(
+        &self,
+        session_id: Option<&str>,
+        id: &str,
+    ) -> Result<AnyUser, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_user(&conn, &session, Some(id), None)
+    }
+
+    /// GET /v0/users
+    pub fn get_users(
+        &self,
+        session_id: Option<&str>,
+        limit: Option<i64>,
+        before: Option<&str>,
+    ) -> Result<Vec<HistoricalUser>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_users(&conn, &session, limit, before)
+    }
+
+    /// GET /v0/groups
+    pub fn get_groups(
+        &self,
+        session_id: Option<&str>,
+        before_time: Option<u64>,
+    ) -> Result<Vec<HistoricalGroup>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_groups(&conn, &session, before_time)
+    }
+
+    /// GET /v0/group/[slug]
+    pub fn get_group(
+        &self,
+        session_id: Option<&str>,
+        slug: &str,
+    ) -> Result<HistoricalGroup, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_group(&conn, &session, None, Some(slug))
+    }
+
+    /// GET /v0/group/by-id/[id]
+    pub fn get_group_by_id(
+        &self,
+        session_id: Option<&str>,
+        id: &str,
+    ) -> Result<HistoricalGroup, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_group(&conn, &session, Some(id), None)
+    }
+
+    /// GET /v0/group/by-id/[id]/markets
+    pub fn get_group_markets(
+        &self,
+        session_id: Option<&str>,
+        id: &str,
+    ) -> Result<Vec<LiteMarket>, RowParsingError> {
+        let (conn, session) = self.conn_and_session(session_id)?;
+        db::get_group_markets(&conn, &session, id)
+    }
+}
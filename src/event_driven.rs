@@ -0,0 +1,182 @@
+//! Event-driven clock mode. Once a session's bots have had no requests in
+//! flight for a while, the clock jumps straight to the next event they could
+//! care about, so backtests don't spend most of their time waiting through
+//! stretches where nothing happens.
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+
+use crate::data_types::ClockMode;
+use crate::db;
+use crate::db::db_common::get_db_connection;
+use crate::server::SESSION_HEADER;
+
+struct SessionActivity {
+    in_flight: usize,
+
+    /// when the last request finished, or the clock last moved
+    last_active: Instant,
+}
+
+impl SessionActivity {
+    fn new() -> Self {
+        SessionActivity {
+            in_flight: 0,
+            last_active: Instant::now(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Activity {
+    /// session id -> its requests
+    sessions: Mutex<HashMap<String, SessionActivity>>,
+}
+
+/// Counts a request as in flight until it's dropped
+pub struct RequestGuard {
+    activity: Arc<Activity>,
+    session_id: Option<String>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if let Some(session_id) = &self.session_id {
+            let mut sessions = self.activity.sessions.lock().unwrap();
+            if let Some(session) = sessions.get_mut(session_id) {
+                session.in_flight -= 1;
+                session.last_active = Instant::now();
+            }
+        }
+    }
+}
+
+impl Activity {
+    fn begin(self: &Arc<Self>, session_id: Option<String>) -> RequestGuard {
+        if let Some(session_id) = &session_id {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions
+                .entry(session_id.clone())
+                .or_insert_with(SessionActivity::new)
+                .in_flight += 1;
+        }
+
+        RequestGuard {
+            activity: self.clone(),
+            session_id,
+        }
+    }
+
+    /// Moves on every event-driven session whose bots have been idle long enough.
+    /// Sessions that were deleted or left event-driven mode are forgotten.
+    pub fn skip_idle_time(&self, conn: &Connection) {
+        let session_ids = match db::get_session_ids_with_clock_mode(conn, "event-driven") {
+            Ok(session_ids) => session_ids,
+            Err(e) => {
+                log::error!("failed to find event-driven sessions: {e}");
+                return;
+            }
+        };
+
+        // the sessions with nothing in flight, and how long they've been quiet for.
+        // The lock isn't held while touching the db.
+        let quiet: Vec<(String, Duration)> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|session_id, activity| {
+                activity.in_flight > 0 || session_ids.contains(session_id)
+            });
+
+            session_ids
+                .into_iter()
+                .filter_map(|session_id| {
+                    // sessions nobody has talked to yet get a grace period from when they're first seen
+                    let activity = sessions
+                        .entry(session_id.clone())
+                        .or_insert_with(SessionActivity::new);
+
+                    (activity.in_flight == 0).then(|| (session_id, activity.last_active.elapsed()))
+                })
+                .collect()
+        };
+
+        for (session_id, quiet_for) in quiet {
+            let session = match db::get_session(conn, &session_id) {
+                Ok(session) => session,
+                Err(e) => {
+                    log::error!("failed to get session {session_id}: {e}");
+                    continue;
+                }
+            };
+
+            let idle = match session.clock_mode {
+                ClockMode::EventDriven { idle_ms } => Duration::from_millis(idle_ms),
+                _ => continue,
+            };
+            if quiet_for < idle {
+                continue;
+            }
+
+            match db::next_relevant_event_time(conn, &session) {
+                Ok(Some(to)) => {
+                    log::debug!("session {session_id} is idle, jumping to {to}");
+                    if let Err(e) = db::advance_session_clock(conn, &session_id, to) {
+                        log::error!("failed to advance session {session_id}: {e}");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("failed to find the next event for session {session_id}: {e}")
+                }
+            }
+
+            // whether or not it moved, give the bots another idle period before trying again
+            if let Some(activity) = self.sessions.lock().unwrap().get_mut(&session_id) {
+                activity.last_active = Instant::now();
+            }
+        }
+    }
+}
+
+/// Wraps `filter` so that its requests count as activity in their session
+pub fn track<F, R>(
+    activity: Arc<Activity>,
+    filter: F,
+) -> impl Filter<Extract = (R,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply + Send,
+{
+    warp::header::optional::<String>(SESSION_HEADER)
+        .map(move |session_id: Option<String>| activity.begin(session_id))
+        .and(filter)
+        .map(|_guard: RequestGuard, reply: R| reply)
+}
+
+/// Checks for idle sessions every so often, forever. The db work happens on the
+/// blocking pool, off the async workers.
+pub async fn run_event_driven_clocks(
+    activity: Arc<Activity>,
+    connection_pool: Arc<Pool<SqliteConnectionManager>>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(50));
+    loop {
+        interval.tick().await;
+
+        let activity = activity.clone();
+        let connection_pool = connection_pool.clone();
+        let skipped = tokio::task::spawn_blocking(move || {
+            let conn = get_db_connection(connection_pool);
+            activity.skip_idle_time(&conn);
+        })
+        .await;
+
+        if let Err(e) = skipped {
+            log::error!("failed to move event-driven clocks: {e}");
+        }
+    }
+}
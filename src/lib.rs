@@ -0,0 +1,19 @@
+//! A backtest server for Manifold Markets. `BacktestEngine` runs the backtest
+//! in-process, and `server::serve` puts the Manifold api in front of it.
+
+mod compat;
+mod control;
+pub mod data_types;
+mod db;
+mod engine;
+mod event_driven;
+mod lockstep;
+mod realtime;
+pub mod server;
+mod webhooks;
+mod websocket;
+
+pub use crate::db::{
+    RowParsingError, DEFAULT_SESSION_ID, DEFAULT_SNAPSHOT_DIR, DEFAULT_STARTING_BALANCE,
+};
+pub use crate::engine::BacktestEngine;
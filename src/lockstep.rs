@@ -0,0 +1,237 @@
+//! Lockstep clock mode. Bots register with a session, and the session clock only
+//! moves to the next historical event once every registered bot has said it's
+//! ready (or the round times out). A bot's ready request blocks until the clock
+//! has moved, so competing bots always see the same sequence of states, no matter
+//! how fast each of them is.
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::data_types::ClockMode;
+use crate::db;
+use crate::db::db_common::get_db_connection;
+
+struct Round {
+    /// bot id -> whether it's ready
+    bots: HashMap<String, bool>,
+
+    /// when the clock last moved, for timing out stragglers
+    started: Instant,
+
+    /// set while the clock is being moved, so it's only moved once per round
+    advancing: bool,
+
+    /// bumped every time the clock moves
+    advanced: watch::Sender<u64>,
+}
+
+impl Round {
+    fn new() -> Self {
+        Round {
+            bots: HashMap::new(),
+            started: Instant::now(),
+            advancing: false,
+            advanced: watch::channel(0).0,
+        }
+    }
+
+    /// Whether every bot is ready and the clock isn't already being moved. If so,
+    /// the caller has to move it, with Lockstep::advance.
+    fn claim_if_all_ready(&mut self) -> bool {
+        let claimed =
+            !self.advancing && !self.bots.is_empty() && self.bots.values().all(|ready| *ready);
+        self.advancing |= claimed;
+        claimed
+    }
+}
+
+#[derive(Default)]
+pub struct Lockstep {
+    /// session id -> the round it's in. Never held while touching the db.
+    rounds: Mutex<HashMap<String, Round>>,
+}
+
+fn lockstep_timeout(conn: &Connection, session_id: &str) -> Result<Duration, String> {
+    let session = db::get_session(conn, session_id).map_err(|e| e.to_string())?;
+    match session.clock_mode {
+        ClockMode::Lockstep { timeout_ms } => Ok(Duration::from_millis(timeout_ms)),
+        _ => Err(format!("session {session_id} isn't in lockstep mode")),
+    }
+}
+
+impl Lockstep {
+    pub fn register(
+        &self,
+        conn: &Connection,
+        session_id: &str,
+        bot_id: &str,
+    ) -> Result<(), String> {
+        lockstep_timeout(conn, session_id)?;
+
+        let mut rounds = self.rounds.lock().unwrap();
+        let round = rounds
+            .entry(session_id.to_string())
+            .or_insert_with(Round::new);
+        round.bots.insert(bot_id.to_string(), false);
+
+        log::info!("bot {bot_id} joined session {session_id}");
+
+        Ok(())
+    }
+
+    /// Removes the bot from the session's round. The bot that left might have
+    /// been the one everyone was waiting for, so this can move the clock.
+    pub fn unregister(
+        &self,
+        conn: &Connection,
+        session_id: &str,
+        bot_id: &str,
+    ) -> Result<(), String> {
+        let claimed = {
+            let mut rounds = self.rounds.lock().unwrap();
+            let round = rounds
+                .get_mut(session_id)
+                .filter(|round| round.bots.contains_key(bot_id))
+                .ok_or_else(|| {
+                    format!("bot {bot_id} isn't registered with session {session_id}")
+                })?;
+            round.bots.remove(bot_id);
+            round.claim_if_all_ready()
+        };
+
+        log::info!("bot {bot_id} left session {session_id}");
+
+        if claimed {
+            self.advance(conn, session_id);
+        }
+
+        Ok(())
+    }
+
+    /// Marks the bot as ready. The returned receiver changes once the clock has
+    /// moved, which happens before this returns if this was the last bot to get ready.
+    pub fn ready(
+        &self,
+        conn: &Connection,
+        session_id: &str,
+        bot_id: &str,
+    ) -> Result<watch::Receiver<u64>, String> {
+        lockstep_timeout(conn, session_id)?;
+
+        let (advanced, claimed) = {
+            let mut rounds = self.rounds.lock().unwrap();
+            let round = rounds
+                .get_mut(session_id)
+                .filter(|round| round.bots.contains_key(bot_id))
+                .ok_or_else(|| {
+                    format!("bot {bot_id} isn't registered with session {session_id}")
+                })?;
+
+            let advanced = round.advanced.subscribe();
+            round.bots.insert(bot_id.to_string(), true);
+            (advanced, round.claim_if_all_ready())
+        };
+
+        if claimed {
+            self.advance(conn, session_id);
+        }
+
+        Ok(advanced)
+    }
+
+    /// Moves the session to its next event, and starts a new round. Only called
+    /// by whoever claimed the round's move, and without holding the rounds lock.
+    fn advance(&self, conn: &Connection, session_id: &str) {
+        let next_event_time = db::get_session(conn, session_id)
+            .and_then(|session| db::next_event_time(conn, &session));
+
+        match next_event_time {
+            Ok(Some(to)) => {
+                if let Err(e) = db::advance_session_clock(conn, session_id, to) {
+                    log::error!("failed to advance session {session_id}: {e}");
+                }
+            }
+            Ok(None) => log::info!("session {session_id} has no events left"),
+            Err(e) => log::error!("failed to find the next event for session {session_id}: {e}"),
+        }
+
+        let mut rounds = self.rounds.lock().unwrap();
+        if let Some(round) = rounds.get_mut(session_id) {
+            for ready in round.bots.values_mut() {
+                *ready = false;
+            }
+            round.started = Instant::now();
+            round.advancing = false;
+            round.advanced.send_modify(|n| *n += 1);
+        }
+    }
+
+    /// Moves on any round that has waited longer than its session's timeout.
+    /// Rounds for sessions that were deleted or left lockstep mode are dropped.
+    pub fn expire_rounds(&self, conn: &Connection) {
+        let session_ids: Vec<String> = self.rounds.lock().unwrap().keys().cloned().collect();
+
+        for session_id in session_ids {
+            let timeout = match lockstep_timeout(conn, &session_id) {
+                Ok(timeout) => timeout,
+                Err(e) => {
+                    log::info!("dropping lockstep round: {e}");
+                    if let Some(round) = self.rounds.lock().unwrap().remove(&session_id) {
+                        // wake up anyone still waiting, so they don't hang forever
+                        round.advanced.send_modify(|n| *n += 1);
+                    }
+                    continue;
+                }
+            };
+
+            let claimed = {
+                let mut rounds = self.rounds.lock().unwrap();
+                match rounds.get_mut(&session_id) {
+                    Some(round)
+                        if !round.advancing
+                            && !round.bots.is_empty()
+                            && round.started.elapsed() >= timeout =>
+                    {
+                        round.advancing = true;
+                        true
+                    }
+                    _ => false,
+                }
+            };
+
+            if claimed {
+                log::info!("lockstep round for session {session_id} timed out");
+                self.advance(conn, &session_id);
+            }
+        }
+    }
+}
+
+/// Checks for timed out rounds every so often, forever. The db work happens on
+/// the blocking pool, off the async workers.
+pub async fn run_timeouts(
+    lockstep: Arc<Lockstep>,
+    connection_pool: Arc<Pool<SqliteConnectionManager>>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(50));
+    loop {
+        interval.tick().await;
+
+        let lockstep = lockstep.clone();
+        let connection_pool = connection_pool.clone();
+        let expired = tokio::task::spawn_blocking(move || {
+            let conn = get_db_connection(connection_pool);
+            lockstep.expire_rounds(&conn);
+        })
+        .await;
+
+        if let Err(e) = expired {
+            log::error!("failed to expire lockstep rounds: {e}");
+        }
+    }
+}
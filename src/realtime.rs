@@ -0,0 +1,35 @@
+//! Realtime clock mode. The session clock runs continuously at some multiple of
+//! the wall clock, so bots can be pointed at the server unchanged and run through
+//! history faster than it happened. The clock is moved forward in small steps by
+//! a background task, which also fills limit orders and settles resolutions as
+//! it goes.
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db;
+use crate::db::db_common::get_db_connection;
+
+/// Moves the realtime sessions' clocks along every so often, forever. The db
+/// work happens on the blocking pool, off the async workers.
+pub async fn run_realtime_clocks(connection_pool: Arc<Pool<SqliteConnectionManager>>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+
+        let connection_pool = connection_pool.clone();
+        let synced = tokio::task::spawn_blocking(move || {
+            let conn = get_db_connection(connection_pool);
+            db::sync_realtime_clocks(&conn)
+        })
+        .await;
+
+        match synced {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => log::error!("failed to move realtime clocks: {e}"),
+            Err(e) => log::error!("failed to move realtime clocks: {e}"),
+        }
+    }
+}
@@ -0,0 +1,498 @@
+//! The HTTP server: the Manifold api, served from a BacktestEngine, along with
+//! the backtest-only routes in control.rs, websocket.rs and compat.rs.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use warp::{http::StatusCode, Filter, Reply};
+
+use crate::data_types::{BetQuery, BetRequest, MarketQuery, SearchMarketsQuery};
+use crate::db::RowParsingError;
+use crate::engine::BacktestEngine;
+use crate::event_driven::Activity;
+use crate::{compat, control, event_driven, websocket};
+
+/// Picks the backtest session a request runs in. Without it, requests go to the default session.
+pub(crate) const SESSION_HEADER: &str = "x-backtest-session";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupQueryParams {
+    #[serde(rename = "beforeTime")]
+    before_time: Option<u64>,
+    #[serde(rename = "availableToUserId")]
+    _available_to_user_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserQueryParams {
+    limit: Option<i64>,
+    before: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PositionQueryParams {
+    #[serde(rename = "userId")]
+    user_id: Option<String>,
+}
+
+/// Manifold's error body
+#[derive(Debug, Serialize)]
+struct HttpError {
+    message: String,
+}
+
+pub(crate) fn ret_http_error(code: u16, message: String) -> warp::reply::Response {
+    log::error!("{}", message);
+    let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    warp::reply::with_status(warp::reply::json(&HttpError { message }), status).into_response()
+}
+
+/// Replies with the status that fits the error. Database and serialization
+/// errors are our fault and say nothing useful to a bot, so they're only logged.
+pub(crate) fn ret_db_error(e: RowParsingError) -> warp::reply::Response {
+    match e {
+        RowParsingError::MarketNotFound(message)
+        | RowParsingError::UserNotFound(message)
+        | RowParsingError::GroupNotFound(message)
+        | RowParsingError::SessionNotFound(message) => ret_http_error(404, message),
+        RowParsingError::InvalidRequest(message) => ret_http_error(400, message),
+        RowParsingError::Generic(_)
+        | RowParsingError::RusqliteError(_)
+        | RowParsingError::SerdeError(_) => {
+            log::error!("internal error: {e}");
+            ret_http_error(500, "internal error".to_string())
+        }
+    }
+}
+
+/// A query parameter that's unknown or doesn't parse, named in the message
+#[derive(Debug)]
+struct InvalidParam(String);
+
+impl warp::reject::Reject for InvalidParam {}
+
+/// Like `warp::query`, but a bad parameter is rejected with a message naming
+/// it, instead of warp's bare "Invalid query string"
+pub(crate) fn strict_query<T: DeserializeOwned + Send + 'static>(
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .and_then(|query: String| async move {
+            parse_query::<T>(&query).map_err(|message| warp::reject::custom(InvalidParam(message)))
+        })
+}
+
+fn parse_query<T: DeserializeOwned>(query: &str) -> Result<T, String> {
+    serde_urlencoded::from_str(query).map_err(|e| {
+        // serde only names unknown parameters, so find the one that fails on its own
+        let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+        let bad_pair = pairs.iter().find(|pair| {
+            serde_urlencoded::to_string([pair]).map_or(true, |alone| {
+                serde_urlencoded::from_str::<T>(&alone).is_err()
+            })
+        });
+        match bad_pair {
+            Some((name, value)) => format!("invalid parameter {name}={value}: {e}"),
+            None => format!("invalid query string: {e}"),
+        }
+    })
+}
+
+/// Gives warp's own rejections (unknown routes, bad query strings and bodies)
+/// the same error body as everything else
+async fn handle_rejection(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::Response, std::convert::Infallible> {
+    let reply = if rejection.is_not_found() {
+        ret_http_error(404, "not found".to_string())
+    } else if let Some(InvalidParam(message)) = rejection.find::<InvalidParam>() {
+        ret_http_error(400, message.clone())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        ret_http_error(400, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        ret_http_error(400, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MissingHeader>() {
+        ret_http_error(400, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+        ret_http_error(400, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        ret_http_error(415, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        ret_http_error(413, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        ret_http_error(405, e.to_string())
+    } else {
+        log::error!("unhandled rejection: {rejection:?}");
+        ret_http_error(500, "internal error".to_string())
+    };
+
+    Ok(reply)
+}
+
+/// Pulls the key out of Manifold's `Authorization: Key <key>` header
+fn parse_api_key(authorization: Option<String>) -> Result<Option<String>, String> {
+    match authorization {
+        None => Ok(None),
+        Some(header) => match header.strip_prefix("Key ") {
+            Some(key) if !key.trim().is_empty() => Ok(Some(key.trim().to_string())),
+            _ => Err("malformed authorization header, expected 'Key <api key>'".to_string()),
+        },
+    }
+}
+
+/// Serves the api on `addr`, and runs the clocks and webhooks of the engine's
+/// sessions in the background
+pub async fn serve(engine: BacktestEngine, addr: impl Into<SocketAddr>) {
+    let activity = Arc::new(Activity::default());
+    engine.start_background_tasks(activity.clone());
+
+    let root = warp::path::end().map(|| StatusCode::NOT_IMPLEMENTED);
+    let v0 = warp::path("v0");
+    let base = warp::path("v0")
+        .and(warp::path::end())
+        .map(|| StatusCode::NOT_IMPLEMENTED);
+
+    // MAJOR TODO
+    //
+    // Listing out all these endpoints is messy. I want to move the
+    // endpoint construction somewhere else. How do we do this?
+    let engine_clone = engine.clone();
+    let markets_endpoint = v0
+        .and(warp::path("markets"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(strict_query::<MarketQuery>())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |mq: MarketQuery, session_id: Option<String>| {
+            match engine_clone.get_markets(session_id.as_deref(), &mq) {
+                Ok(markets) => {
+                    log::info!("returning {} markets", markets.len());
+                    warp::reply::json(&markets).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let search_markets_endpoint = v0
+        .and(warp::path("search-markets"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(strict_query::<SearchMarketsQuery>())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |sq: SearchMarketsQuery, session_id: Option<String>| {
+            match engine_clone.search_markets(session_id.as_deref(), &sq) {
+                Ok(markets) => warp::reply::json(&markets).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let market_by_id_endpoint = v0
+        // /v0/markets/[id] is where this used to be, so it's kept as an alias
+        .and(warp::path("market").or(warp::path("markets")).unify())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |market_id: String, session_id: Option<String>| {
+            match engine_clone.get_market(session_id.as_deref(), &market_id) {
+                Ok(market) => {
+                    log::info!("returning market with id {market_id}");
+                    warp::reply::json(&market).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let bets_endpoint = v0
+        .and(warp::path("bets"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(strict_query::<BetQuery>())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |bq: BetQuery, session_id: Option<String>| {
+            match engine_clone.get_bets(session_id.as_deref(), &bq) {
+                Ok(bets) => {
+                    log::info!("returning {} bets", bets.len());
+                    warp::reply::json(&bets).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let market_by_slug_endpoint = v0
+        .and(warp::path("slug"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |slug: String, session_id: Option<String>| {
+            match engine_clone.get_market_by_slug(session_id.as_deref(), &slug) {
+                Ok(market) => {
+                    log::info!("returning market with slug {slug}");
+                    warp::reply::json(&market).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let me_endpoint = v0
+        .and(warp::path("me"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(
+            move |authorization: Option<String>, session_id: Option<String>| {
+                let api_key = match parse_api_key(authorization) {
+                    Ok(api_key) => api_key,
+                    Err(e) => return ret_http_error(401, e),
+                };
+
+                match engine_clone.get_me(session_id.as_deref(), api_key.as_deref()) {
+                    Ok(me) => warp::reply::json(&me).into_response(),
+                    Err(e) => ret_db_error(e),
+                }
+            },
+        );
+
+    let engine_clone = engine.clone();
+    let user_endpoint = v0
+        .and(warp::path("user"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |username: String, session_id: Option<String>| {
+            match engine_clone.get_user(session_id.as_deref(), &username) {
+                Ok(user) => warp::reply::json(&user).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let user_by_id_endpoint = v0
+        .and(warp::path("user"))
+        .and(warp::path("by-id"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |user_id: String, session_id: Option<String>| {
+            match engine_clone.get_user_by_id(session_id.as_deref(), &user_id) {
+                Ok(user) => warp::reply::json(&user).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let users_endpoint = v0
+        .and(warp::path("users"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(strict_query::<UserQueryParams>())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |uq: UserQueryParams, session_id: Option<String>| {
+            match engine_clone.get_users(session_id.as_deref(), uq.limit, uq.before.as_deref()) {
+                Ok(users) => {
+                    log::info!("returning {} users", users.len());
+                    warp::reply::json(&users).into_response()
+                }
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let groups_endpoint = v0
+        .and(warp::path("groups"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(strict_query::<GroupQueryParams>())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |gq: GroupQueryParams, session_id: Option<String>| {
+            match engine_clone.get_groups(session_id.as_deref(), gq.before_time) {
+                Ok(groups) => warp::reply::json(&groups).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let group_endpoint = v0
+        .and(warp::path("group"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |slug: String, session_id: Option<String>| {
+            match engine_clone.get_group(session_id.as_deref(), &slug) {
+                Ok(group) => warp::reply::json(&group).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let group_by_id_endpoint = v0
+        .and(warp::path("group"))
+        .and(warp::path("by-id"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |group_id: String, session_id: Option<String>| {
+            match engine_clone.get_group_by_id(session_id.as_deref(), &group_id) {
+                Ok(group) => warp::reply::json(&group).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let group_markets_endpoint = v0
+        .and(warp::path("group"))
+        .and(warp::path("by-id"))
+        .and(warp::path::param())
+        .and(warp::path("markets"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |group_id: String, session_id: Option<String>| {
+            match engine_clone.get_group_markets(session_id.as_deref(), &group_id) {
+                Ok(markets) => warp::reply::json(&markets).into_response(),
+                Err(e) => ret_db_error(e),
+            }
+        });
+
+    let engine_clone = engine.clone();
+    let bet_endpoint = v0
+        .and(warp::path("bet"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json::<BetRequest>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(
+            move |br: BetRequest, authorization: Option<String>, session_id: Option<String>| {
+                let api_key = match parse_api_key(authorization) {
+                    Ok(api_key) => api_key,
+                    Err(e) => return ret_http_error(401, e),
+                };
+
+                match engine_clone.place_bet(session_id.as_deref(), api_key.as_deref(), &br) {
+                    Ok(bet) => warp::reply::json(&bet).into_response(),
+                    Err(e) => ret_db_error(e),
+                }
+            },
+        );
+
+    let engine_clone = engine.clone();
+    let cancel_bet_endpoint = v0
+        .and(warp::path("bet"))
+        .and(warp::path("cancel"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(
+            move |bet_id: String, authorization: Option<String>, session_id: Option<String>| {
+                let api_key = match parse_api_key(authorization) {
+                    Ok(api_key) => api_key,
+                    Err(e) => return ret_http_error(401, e),
+                };
+
+                match engine_clone.cancel_bet(session_id.as_deref(), api_key.as_deref(), &bet_id) {
+                    Ok(bet) => warp::reply::json(&bet).into_response(),
+                    Err(e) => ret_db_error(e),
+                }
+            },
+        );
+
+    let engine_clone = engine.clone();
+    let positions_endpoint = v0
+        .and(warp::path("market"))
+        .and(warp::path::param())
+        .and(warp::path("positions"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(strict_query::<PositionQueryParams>())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(
+            move |market_id: String, pq: PositionQueryParams, session_id: Option<String>| {
+                let positions = engine_clone.get_positions(
+                    session_id.as_deref(),
+                    &market_id,
+                    pq.user_id.as_deref(),
+                );
+
+                match positions {
+                    Ok(positions) => warp::reply::json(&positions).into_response(),
+                    Err(e) => ret_db_error(e),
+                }
+            },
+        );
+
+    // requests to the Manifold api count as bot activity, for the event-driven clock
+    // boxed in groups, since one long chain of filters takes minutes to compile
+    let market_routes = markets_endpoint
+        .or(market_by_id_endpoint)
+        .unify()
+        .or(search_markets_endpoint)
+        .unify()
+        .or(market_by_slug_endpoint)
+        .unify()
+        .or(positions_endpoint)
+        .unify()
+        .boxed();
+
+    let user_routes = me_endpoint
+        .or(user_endpoint)
+        .unify()
+        .or(user_by_id_endpoint)
+        .unify()
+        .or(users_endpoint)
+        .unify()
+        .boxed();
+
+    let group_routes = groups_endpoint
+        .or(group_endpoint)
+        .unify()
+        .or(group_by_id_endpoint)
+        .unify()
+        .or(group_markets_endpoint)
+        .unify()
+        .boxed();
+
+    let bet_routes = bets_endpoint
+        .or(bet_endpoint)
+        .unify()
+        .or(cancel_bet_endpoint)
+        .unify()
+        .boxed();
+
+    let api_routes = market_routes
+        .or(user_routes)
+        .unify()
+        .or(group_routes)
+        .unify()
+        .or(bet_routes)
+        .unify()
+        .boxed();
+
+    let routes = root
+        .or(base)
+        .or(event_driven::track(activity, api_routes))
+        .or(websocket::route(engine.clone()))
+        .or(control::routes(engine))
+        .or(compat::routes())
+        .recover(handle_rejection);
+
+    warp::serve(routes).run(addr).await;
+}
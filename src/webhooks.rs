@@ -0,0 +1,425 @@
+//! Webhooks, for harnesses that would rather be called than hold a websocket
+//! open. Each session with webhooks gets a task that reads the session's events
+//! as the clock passes them, once for all of its webhooks and only the kinds
+//! they want, and hands them out. Each webhook then gets its own task, which
+//! POSTs them to the webhook's url one at a time, in the order they happened.
+//! Bodies look like
+//! `{"webhookId": id, "sessionId": id, "type": event type, "data": Bet or LiteMarket}`.
+
+use hyper::{Body, Client, Request};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::data_types::{Webhook, WebhookEvent};
+use crate::db;
+use crate::db::db_common::get_db_connection;
+use crate::db::{EventCursor, EventFilter, Markets, SessionEvent};
+
+/// The body to send for `event`, if the webhook wants it
+fn payload(session_id: &str, webhook: &Webhook, event: &SessionEvent) -> Option<Value> {
+    let (event_type, data) = match event {
+        SessionEvent::NewContract(market) => (WebhookEvent::MarketCreated, json!(market)),
+        SessionEvent::NewBet(bet) if webhook.market_ids.contains(&bet.contract_id) => {
+            (WebhookEvent::Bet, json!(bet))
+        }
+        SessionEvent::NewBet(_) => return None,
+        SessionEvent::OrderFilled(bet) => (WebhookEvent::LimitFill, json!(bet)),
+        SessionEvent::Resolved(market) => (WebhookEvent::Resolution, json!(market)),
+    };
+
+    if !webhook.events.contains(&event_type) {
+        return None;
+    }
+
+    Some(json!({
+        "webhookId": webhook.id,
+        "sessionId": session_id,
+        "type": event_type,
+        "data": data,
+    }))
+}
+
+/// The events that `webhook` is sent
+fn event_filter(webhook: &Webhook) -> EventFilter {
+    let mut filter = EventFilter::default();
+    for event in &webhook.events {
+        match event {
+            WebhookEvent::MarketCreated => filter.new_contracts = true,
+            WebhookEvent::Bet => {
+                for market_id in &webhook.market_ids {
+                    filter.bets.insert(market_id);
+                }
+            }
+            WebhookEvent::LimitFill => filter.order_fills = Markets::All,
+            WebhookEvent::Resolution => filter.resolutions = Markets::All,
+        }
+    }
+    filter
+}
+
+async fn post(
+    client: &Client<hyper::client::HttpConnector>,
+    url: &str,
+    body: Value,
+) -> Result<(), String> {
+    let request = Request::post(url)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .map_err(|e| e.to_string())?;
+
+    let response = tokio::time::timeout(Duration::from_secs(5), client.request(request))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    if !response.status().is_success() {
+        return Err(format!("got status {}", response.status()));
+    }
+
+    Ok(())
+}
+
+/// One webhook's events, which its own task POSTs one at a time, so that a slow
+/// receiver doesn't hold up the session's other webhooks
+struct Delivery {
+    webhook: Webhook,
+
+    /// where the webhook's events have been read up to
+    cursor: EventCursor,
+
+    bodies: mpsc::UnboundedSender<Value>,
+}
+
+impl Delivery {
+    fn start(webhook: Webhook, cursor: EventCursor) -> Self {
+        let (bodies, mut queued) = mpsc::unbounded_channel::<Value>();
+
+        let url = webhook.url.clone();
+        let webhook_id = webhook.id.clone();
+        tokio::spawn(async move {
+            let client = Client::new();
+            while let Some(body) = queued.recv().await {
+                // a receiver that's down misses the event, rather than holding up the rest
+                if let Err(e) = post(&client, &url, body).await {
+                    log::error!("failed to call webhook {webhook_id} at {url}: {e}");
+                }
+            }
+        });
+
+        Delivery {
+            webhook,
+            cursor,
+            bodies,
+        }
+    }
+}
+
+/// One look at a session, for all of its webhooks
+struct SessionRead {
+    /// the session's webhooks that still exist
+    webhook_ids: HashSet<String>,
+
+    /// where the session is now
+    to: EventCursor,
+
+    /// the events for each of the reads asked for, in the same order
+    events: Vec<Vec<SessionEvent>>,
+}
+
+/// Reads the events each (cursor, filter) in `reads` is owed, up to now.
+/// Errors once the session is gone.
+fn read_events(
+    connection_pool: Arc<Pool<SqliteConnectionManager>>,
+    session_id: &str,
+    reads: Vec<(EventCursor, EventFilter)>,
+) -> Result<SessionRead, String> {
+    let conn = get_db_connection(connection_pool);
+
+    let webhook_ids = db::get_webhooks(&conn, session_id)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|webhook| webhook.id)
+        .collect();
+
+    let session = db::get_session(&conn, session_id).map_err(|e| e.to_string())?;
+    let to = db::event_cursor(&conn, &session).map_err(|e| e.to_string())?;
+
+    let mut events = Vec::new();
+    for (from, filter) in reads {
+        events.push(
+            db::get_events_between(&conn, &session, from, to, &filter)
+                .map_err(|e| e.to_string())?,
+        );
+    }
+
+    Ok(SessionRead {
+        webhook_ids,
+        to,
+        events,
+    })
+}
+
+/// The running webhooks, with a task for each session that has any
+pub struct Webhooks {
+    connection_pool: Arc<Pool<SqliteConnectionManager>>,
+
+    /// session id -> where its task picks up newly added webhooks
+    sessions: Mutex<HashMap<String, mpsc::UnboundedSender<(Webhook, EventCursor)>>>,
+}
+
+impl Webhooks {
+    pub fn new(connection_pool: Arc<Pool<SqliteConnectionManager>>) -> Self {
+        Webhooks {
+            connection_pool,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts sending the session's events from `cursor` on to the webhook
+    pub fn start(self: &Arc<Self>, session_id: &str, webhook: Webhook, cursor: EventCursor) {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let unsent = match sessions.get(session_id) {
+            Some(new_webhooks) => new_webhooks.send((webhook, cursor)).err().map(|e| e.0),
+            None => Some((webhook, cursor)),
+        };
+
+        // the session has no task yet, or its task is gone
+        if let Some(first_webhook) = unsent {
+            let (new_webhooks, receiver) = mpsc::unbounded_channel();
+            new_webhooks.send(first_webhook).unwrap();
+            sessions.insert(session_id.to_string(), new_webhooks);
+
+            tokio::spawn(self.clone().deliver(session_id.to_string(), receiver));
+        }
+    }
+
+    /// Starts every webhook in the db, from each session's current state.
+    /// Events from while the server was down aren't sent.
+    pub fn start_all(self: &Arc<Self>) {
+        let conn = get_db_connection(self.connection_pool.clone());
+
+        let webhooks = db::get_all_webhooks(&conn).expect("failed to get webhooks");
+        for (session_id, webhook) in webhooks {
+            let cursor = db::get_session(&conn, &session_id)
+                .and_then(|session| db::event_cursor(&conn, &session));
+
+            match cursor {
+                Ok(cursor) => self.start(&session_id, webhook, cursor),
+                Err(e) => log::error!("failed to start webhook {}: {e}", webhook.id),
+            }
+        }
+    }
+
+    /// Reads the session's events every so often, and hands them out to its
+    /// webhooks, until it has none left
+    async fn deliver(
+        self: Arc<Self>,
+        session_id: String,
+        mut new_webhooks: mpsc::UnboundedReceiver<(Webhook, EventCursor)>,
+    ) {
+        let mut deliveries: Vec<Delivery> = Vec::new();
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+
+        loop {
+            interval.tick().await;
+
+            while let Ok((webhook, cursor)) = new_webhooks.try_recv() {
+                deliveries.push(Delivery::start(webhook, cursor));
+            }
+
+            // webhooks only differ in where they're up to until their first read,
+            // so this is almost always a single read
+            let mut reads: Vec<(EventCursor, EventFilter)> = Vec::new();
+            for delivery in &deliveries {
+                let filter = event_filter(&delivery.webhook);
+                match reads
+                    .iter_mut()
+                    .find(|(cursor, _)| *cursor == delivery.cursor)
+                {
+                    Some((_, read_filter)) => read_filter.extend(&filter),
+                    None => reads.push((delivery.cursor, filter)),
+                }
+            }
+            let cursors: Vec<EventCursor> = reads.iter().map(|(cursor, _)| *cursor).collect();
+
+            let connection_pool = self.connection_pool.clone();
+            let read_session_id = session_id.clone();
+            let read = tokio::task::spawn_blocking(move || {
+                read_events(connection_pool, &read_session_id, reads)
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|read| read);
+
+            match read {
+                Ok(read) => {
+                    deliveries.retain(|delivery| {
+                        let exists = read.webhook_ids.contains(&delivery.webhook.id);
+                        if !exists {
+                            log::info!("stopping webhook {}: it was deleted", delivery.webhook.id);
+                        }
+                        exists
+                    });
+
+                    for delivery in &mut deliveries {
+                        let owed = cursors.iter().position(|cursor| *cursor == delivery.cursor);
+                        for event in owed.map(|owed| &read.events[owed]).into_iter().flatten() {
+                            if let Some(body) = payload(&session_id, &delivery.webhook, event) {
+                                let _ = delivery.bodies.send(body);
+                            }
+                        }
+                        delivery.cursor = read.to;
+                    }
+                }
+                Err(e) => {
+                    log::info!("stopping the webhooks of session {session_id}: {e}");
+                    deliveries.clear();
+                }
+            }
+
+            if deliveries.is_empty() {
+                // checked under the lock, so a webhook can't be handed to this task as it stops
+                let mut sessions = self.sessions.lock().unwrap();
+                match new_webhooks.try_recv() {
+                    Ok((webhook, cursor)) => deliveries.push(Delivery::start(webhook, cursor)),
+                    Err(_) => {
+                        sessions.remove(&session_id);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{BetRequest, ClockMode};
+    use crate::db::test_fixture::{bets, markets, Fixture, DAY, HOUR, START};
+    use warp::Filter;
+
+    /// A stand-in receiver on 127.0.0.1, returning its url and what it's sent
+    fn receiver() -> (String, mpsc::UnboundedReceiver<Value>) {
+        let (sender, received) = mpsc::unbounded_channel();
+
+        let route = warp::post()
+            .and(warp::body::json())
+            .map(move |body: Value| {
+                sender.send(body).unwrap();
+                warp::reply()
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (format!("http://{addr}/hook"), received)
+    }
+
+    /// The next event the receiver gets, as (time, type, id)
+    async fn next_event(received: &mut mpsc::UnboundedReceiver<Value>) -> (u64, String, String) {
+        let body = tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await
+            .expect("timed out waiting for a webhook")
+            .unwrap();
+
+        let data = &body["data"];
+        let event_type = body["type"].as_str().unwrap().to_string();
+        let time = match event_type.as_str() {
+            "resolution" => data["resolutionTime"].as_u64().unwrap(),
+            _ => data["createdTime"].as_u64().unwrap(),
+        };
+        (time, event_type, data["id"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn webhook_events_arrive_in_order_and_once() {
+        let fixture = Fixture::new();
+        let conn = get_db_connection(fixture.connection_pool.clone());
+        let (url, mut received) = receiver();
+
+        let start = START + 2 * HOUR + 30 * 60 * 1000;
+        let session = db::create_session(&conn, Some(start), ClockMode::Manual).unwrap();
+        let cursor = db::event_cursor(&conn, &session).unwrap();
+        let webhook = db::add_webhook(
+            &conn,
+            &session.id,
+            &url,
+            vec![
+                WebhookEvent::MarketCreated,
+                WebhookEvent::Bet,
+                WebhookEvent::Resolution,
+            ],
+            vec!["market01".to_string()],
+        )
+        .unwrap();
+
+        let webhooks = Arc::new(Webhooks::new(fixture.connection_pool.clone()));
+        webhooks.start(&session.id, webhook, cursor);
+
+        let end = START + 11 * DAY;
+        let between = |time: u64| time > start && time <= end;
+        let mut expected: Vec<(u64, String, String)> = Vec::new();
+        for market in markets() {
+            if between(market.created_time) {
+                expected.push((
+                    market.created_time,
+                    "market-created".to_string(),
+                    market.id.clone(),
+                ));
+            }
+            if let Some(resolution_time) = market.resolution_time.filter(|t| between(*t)) {
+                expected.push((resolution_time, "resolution".to_string(), market.id));
+            }
+        }
+        for bet in bets() {
+            if bet.contract_id == "market01" && between(bet.created_time) {
+                expected.push((bet.created_time, "bet".to_string(), bet.id));
+            }
+        }
+        expected.sort();
+        // 6 new markets, 4 bets on market01 and market05's resolution
+        assert_eq!(expected.len(), 11);
+
+        // each move's events, before moving again
+        let mut events = Vec::new();
+        let mut from = start;
+        let mut session = session;
+        for to in [START + 4 * HOUR, START + 8 * HOUR, end] {
+            session = db::advance_session_clock(&conn, &session.id, to).unwrap();
+            let moved_past = expected
+                .iter()
+                .filter(|(time, ..)| *time > from && *time <= to);
+            for _ in 0..moved_past.count() {
+                events.push(next_event(&mut received).await);
+            }
+            from = to;
+        }
+
+        // a bet of our own comes next, so nothing was sent twice
+        let request = BetRequest {
+            contract_id: "market01".to_string(),
+            amount: 10.0,
+            outcome: "YES".to_string(),
+            limit_prob: None,
+            expires_at: None,
+        };
+        let bet = db::place_bet(&conn, &session, Some("bot"), 1000.0, &request).unwrap();
+        assert_eq!(
+            next_event(&mut received).await,
+            (end, "bet".to_string(), bet.id)
+        );
+
+        assert!(events.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        // markets created at the same time can come in either order
+        let mut sorted_events = events.clone();
+        sorted_events.sort();
+        assert_eq!(sorted_events, expected);
+    }
+}
@@ -0,0 +1,223 @@
+//! Websocket api at /ws, in the same message shapes as Manifold's. Clients
+//! subscribe to topics, and are pushed what happens in their session (picked
+//! with the session header on the upgrade request) as its clock moves:
+//!
+//! global/new-contract          `{"contract": LiteMarket}`
+//! global/new-bet               `{"bets": [Bet]}`
+//! global/updated-contract      `{"contract": LiteMarket}`, when a market resolves
+//! contract/[id]                `{"contract": LiteMarket}`, when the market resolves
+//! contract/[id]/new-bet        `{"bets": [Bet]}`
+//! contract/[id]/orders         `{"bets": [Bet]}`, when one of the session's limit orders fills
+//!
+//! Client messages are `{"type": "subscribe" | "unsubscribe", "txid": n, "topics": [..]}`
+//! and `{"type": "ping", "txid": n}`, each answered with
+//! `{"type": "ack", "txid": n, "success": bool}`. Pushes look like
+//! `{"type": "broadcast", "topic": topic, "data": data}`.
+
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::time::Duration;
+use warp::ws::{Message, WebSocket};
+use warp::{Filter, Reply};
+
+use crate::db::{EventCursor, EventFilter, Markets, SessionEvent};
+use crate::engine::BacktestEngine;
+use crate::server::{ret_db_error, SESSION_HEADER};
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum ClientMessage {
+    #[serde(rename = "subscribe")]
+    Subscribe { txid: u64, topics: Vec<String> },
+
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe { txid: u64, topics: Vec<String> },
+
+    #[serde(rename = "ping")]
+    Ping { txid: u64 },
+}
+
+/// The (topic, data) pairs that `event` is broadcast as
+fn broadcasts(event: &SessionEvent) -> Vec<(String, Value)> {
+    match event {
+        SessionEvent::NewContract(market) => {
+            vec![(
+                "global/new-contract".to_string(),
+                json!({ "contract": market }),
+            )]
+        }
+        SessionEvent::NewBet(bet) => vec![
+            ("global/new-bet".to_string(), json!({ "bets": [bet] })),
+            (
+                format!("contract/{}/new-bet", bet.contract_id),
+                json!({ "bets": [bet] }),
+            ),
+        ],
+        SessionEvent::OrderFilled(bet) => vec![(
+            format!("contract/{}/orders", bet.contract_id),
+            json!({ "bets": [bet] }),
+        )],
+        SessionEvent::Resolved(market) => vec![
+            (
+                "global/updated-contract".to_string(),
+                json!({ "contract": market }),
+            ),
+            (
+                format!("contract/{}", market.id),
+                json!({ "contract": market }),
+            ),
+        ],
+    }
+}
+
+/// The events that `topics` are broadcast from
+fn event_filter(topics: &HashSet<String>) -> EventFilter {
+    let mut filter = EventFilter::default();
+    for topic in topics {
+        match topic.split('/').collect::<Vec<_>>().as_slice() {
+            ["global", "new-contract"] => filter.new_contracts = true,
+            ["global", "new-bet"] => filter.bets.extend(&Markets::All),
+            ["global", "updated-contract"] => filter.resolutions.extend(&Markets::All),
+            ["contract", id] => filter.resolutions.insert(id),
+            ["contract", id, "new-bet"] => filter.bets.insert(id),
+            ["contract", id, "orders"] => filter.order_fills.insert(id),
+            _ => {}
+        }
+    }
+    filter
+}
+
+/// Updates the subscriptions, and returns the ack to send back
+fn handle_client_message(text: &str, topics: &mut HashSet<String>) -> Value {
+    match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Subscribe {
+            txid,
+            topics: new_topics,
+        }) => {
+            topics.extend(new_topics);
+            json!({ "type": "ack", "txid": txid, "success": true })
+        }
+        Ok(ClientMessage::Unsubscribe {
+            txid,
+            topics: old_topics,
+        }) => {
+            for topic in &old_topics {
+                topics.remove(topic);
+            }
+            json!({ "type": "ack", "txid": txid, "success": true })
+        }
+        Ok(ClientMessage::Ping { txid }) => json!({ "type": "ack", "txid": txid, "success": true }),
+        Err(e) => {
+            // still ack the message, if it had a txid
+            let txid = serde_json::from_str::<Value>(text)
+                .ok()
+                .and_then(|message| message.get("txid").cloned());
+            json!({ "type": "ack", "txid": txid, "success": false, "error": e.to_string() })
+        }
+    }
+}
+
+/// The session's events since `cursor` that `filter` wants, or an error once
+/// the session is gone. The db work happens on the blocking pool.
+async fn read_events(
+    engine: &BacktestEngine,
+    session_id: &str,
+    cursor: EventCursor,
+    filter: EventFilter,
+) -> Result<(Vec<SessionEvent>, EventCursor), String> {
+    let engine = engine.clone();
+    let session_id = session_id.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        engine
+            .get_events_since(&session_id, cursor, &filter)
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+async fn serve(
+    socket: WebSocket,
+    engine: BacktestEngine,
+    session_id: String,
+    mut cursor: EventCursor,
+) {
+    let (mut tx, mut rx) = socket.split();
+    let mut topics: HashSet<String> = HashSet::new();
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+
+    log::info!("websocket opened on session {session_id}");
+
+    loop {
+        tokio::select! {
+            message = rx.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => break,
+                };
+                if message.is_close() {
+                    break;
+                }
+                let Ok(text) = message.to_str() else {
+                    continue;
+                };
+
+                let ack = handle_client_message(text, &mut topics);
+                if tx.send(Message::text(ack.to_string())).await.is_err() {
+                    break;
+                }
+            }
+            _ = interval.tick() => {
+                // with no subscriptions, this only moves the cursor along
+                let filter = event_filter(&topics);
+                let events = match read_events(&engine, &session_id, cursor, filter).await {
+                    Ok((events, next_cursor)) => {
+                        cursor = next_cursor;
+                        events
+                    }
+                    Err(e) => {
+                        log::info!("closing websocket on session {session_id}: {e}");
+                        break;
+                    }
+                };
+
+                for event in &events {
+                    for (topic, data) in broadcasts(event) {
+                        if !topics.contains(&topic) {
+                            continue;
+                        }
+                        let broadcast = json!({ "type": "broadcast", "topic": topic, "data": data });
+                        if tx.send(Message::text(broadcast.to_string())).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let _ = tx.close().await;
+    log::info!("websocket closed on session {session_id}");
+}
+
+pub fn route(
+    engine: BacktestEngine,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path("ws")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::header::optional::<String>(SESSION_HEADER))
+        .map(move |ws: warp::ws::Ws, session_id: Option<String>| {
+            let (session, cursor) = match engine.event_cursor(session_id.as_deref()) {
+                Ok(session_and_cursor) => session_and_cursor,
+                Err(e) => return ret_db_error(e),
+            };
+
+            let engine = engine.clone();
+            ws.on_upgrade(move |socket| serve(socket, engine, session.id, cursor))
+                .into_response()
+        })
+}